@@ -0,0 +1,189 @@
+//! Checksum verification for downloads.
+//!
+//! Checksums are computed incrementally as data is streamed to disk, so verifying a download doesn't
+//! require reading it back afterwards.
+
+use camino::Utf8Path;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::{fmt, io::Read};
+use thiserror::Error;
+
+/// A supported checksum algorithm.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ChecksumAlgorithm {
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    /// The length of a digest for this algorithm, in hex characters.
+    fn hex_len(self) -> usize {
+        match self {
+            Self::Sha256 | Self::Blake3 => 64,
+            Self::Sha512 => 128,
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake3 => "blake3",
+        })
+    }
+}
+
+/// An expected checksum for a download.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Checksum {
+    pub(crate) algorithm: ChecksumAlgorithm,
+    /// The expected digest, as lowercase hex.
+    pub(crate) expected: String,
+}
+
+impl Checksum {
+    /// Creates a new checksum, validating that `expected` is a hex digest of the right length.
+    pub(crate) fn new(algorithm: ChecksumAlgorithm, expected: &str) -> Result<Self, String> {
+        if expected.len() != algorithm.hex_len() || !expected.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(format!(
+                "invalid {algorithm} checksum `{expected}`: expected {} hex characters",
+                algorithm.hex_len(),
+            ));
+        }
+        Ok(Self {
+            algorithm,
+            expected: expected.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// Computes checksums incrementally, and verifies them against the expected values at the end.
+#[derive(Clone, Debug)]
+pub(crate) struct Verifier {
+    hashers: Vec<(Checksum, Hasher)>,
+}
+
+impl Verifier {
+    pub(crate) fn new(checksums: &[Checksum]) -> Self {
+        Self {
+            hashers: checksums
+                .iter()
+                .map(|checksum| (checksum.clone(), Hasher::new(checksum.algorithm)))
+                .collect(),
+        }
+    }
+
+    /// Returns true if there's nothing to verify.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hashers.is_empty()
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in &mut self.hashers {
+            hasher.update(data);
+        }
+    }
+
+    /// Feeds the first `len` bytes of the file at `path` into the verifier, returning it.
+    ///
+    /// This is used for data that's already on disk, e.g. when resuming a download. The file is
+    /// read on the blocking pool.
+    pub(crate) async fn update_from_file(mut self, path: &Utf8Path, len: u64) -> Result<Self> {
+        let path = path.to_owned();
+        let verifier = tokio::task::spawn_blocking(move || {
+            self.update_from_file_blocking(&path, len)?;
+            Ok::<_, std::io::Error>(self)
+        })
+        .await??;
+        Ok(verifier)
+    }
+
+    fn update_from_file_blocking(&mut self, path: &Utf8Path, len: u64) -> std::io::Result<()> {
+        let f = fs_err::File::open(path)?;
+        let mut reader = std::io::BufReader::new(f).take(len);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            self.update(&buf[..n]);
+        }
+        Ok(())
+    }
+
+    /// Resets all hashers, e.g. because the download restarted from the beginning.
+    pub(crate) fn reset(&mut self) {
+        for (checksum, hasher) in &mut self.hashers {
+            *hasher = Hasher::new(checksum.algorithm);
+        }
+    }
+
+    /// Checks the computed checksums against the expected ones.
+    pub(crate) fn verify(self) -> Result<(), ChecksumMismatch> {
+        for (checksum, hasher) in self.hashers {
+            let actual = hasher.finalize();
+            if actual != checksum.expected {
+                return Err(ChecksumMismatch {
+                    algorithm: checksum.algorithm,
+                    expected: checksum.expected,
+                    actual,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A computed checksum didn't match the expected one.
+#[derive(Debug, Error)]
+#[error("{algorithm} checksum mismatch: expected {expected}, got {actual}")]
+pub(crate) struct ChecksumMismatch {
+    pub(crate) algorithm: ChecksumAlgorithm,
+    pub(crate) expected: String,
+    pub(crate) actual: String,
+}
+
+#[derive(Clone, Debug)]
+enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    // blake3::Hasher is quite large, so box it.
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Self::Sha256(sha2::Sha256::new()),
+            ChecksumAlgorithm::Sha512 => Self::Sha512(sha2::Sha512::new()),
+            ChecksumAlgorithm::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Returns the digest as lowercase hex.
+    fn finalize(self) -> String {
+        match self {
+            Self::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Sha512(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
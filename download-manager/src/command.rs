@@ -2,163 +2,859 @@
 //!
 //! This is where the application's main logic lives. Start reading from DownloadArgs::exec.
 
+mod clean;
+mod client;
+mod daemon;
+mod segmented;
+mod status;
+mod verify;
+
 use crate::{
-    db::{DatabaseTask, DbWorkerHandle, DownloadState},
-    manifest::{Manifest, ManifestEntry},
+    checksum::{Checksum, ChecksumMismatch, Verifier},
+    db::{DatabaseTask, DbWorkerHandle, DownloadRecord, DownloadState, Segment, Validators},
+    events::{Event, Events, MessageFormat, SignalAction},
+    headers::{self, Redacted},
+    limits::Limiter,
+    manifest::{
+        FallbackOptions, HostConfig, HostPattern, Manifest, ManifestEntry, ManifestFormat,
+        STDIN_PATH,
+    },
+    progress::{DownloadProgress, Progress},
+    rate::{Rate, RateLimiter, Throttle},
+    summary::RunSummary,
 };
 use camino::{Utf8Path, Utf8PathBuf};
-use clap::{Args, Parser};
+use clap::{Args, Parser, ValueEnum};
 use eyre::{Result, WrapErr};
 use futures::prelude::*;
-use std::time::Duration;
+use libsw::TokioSw;
+use rand::Rng;
+use reqwest::{
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::SeekFrom,
+    num::NonZeroUsize,
+    pin::Pin,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
 use tokio::{
-    io::AsyncWriteExt,
-    sync::{broadcast, oneshot},
-    time::Instant,
+    io::{AsyncSeekExt, AsyncWriteExt},
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, mpsc},
+    task::{JoinHandle, JoinSet},
+    time::{Instant, Sleep},
 };
 use url::Url;
 
 #[derive(Debug, Parser)]
 pub enum App {
+    /// Download the files in a manifest
     Run(DownloadArgs),
+    /// Show the state of downloads recorded in an output directory
+    Status(status::StatusArgs),
+    /// Check files that were already downloaded against their recorded sizes and checksums
+    Verify(verify::VerifyArgs),
+    /// Delete partial downloads and files that aren't in the manifest from an output directory
+    Clean(clean::CleanArgs),
+    /// Run in the background, downloading files that clients ask for over a Unix domain socket
+    Daemon(daemon::DaemonArgs),
+    /// Ask the daemon to download a URL, or the files in a manifest
+    Add(client::AddArgs),
+    /// List the downloads added to the daemon
+    Ls(client::LsArgs),
+    /// Pause one of the daemon's downloads
+    Pause(client::JobArgs),
+    /// Resume one of the daemon's downloads that was paused
+    Resume(client::JobArgs),
+    /// Cancel one of the daemon's downloads, keeping its partial file
+    Cancel(client::JobArgs),
+    /// Show what the daemon is doing, with progress bars for active downloads
+    Watch(client::WatchArgs),
 }
 
 impl App {
-    pub async fn exec(self) -> Result<()> {
-        tracing::subscriber::set_global_default(tracing_subscriber::FmtSubscriber::new())
-            .expect("tracing subscriber installed");
+    pub async fn exec(self) -> Result<ExitCode> {
+        // Progress bars are only shown while downloading (or watching the daemon download), and
+        // in JSON mode progress is reported through events instead.
+        let human_run = match &self {
+            App::Run(args) => args.message_format == MessageFormat::Human,
+            App::Watch(args) => args.message_format == MessageFormat::Human,
+            _ => false,
+        };
+        let progress = Progress::new(human_run);
+        let builder = tracing_subscriber::FmtSubscriber::builder();
+        let installed = if human_run {
+            // Log lines are written through the progress display, so that they don't get mixed up
+            // with the progress bars.
+            tracing::subscriber::set_global_default(
+                builder.with_writer(progress.log_writer()).finish(),
+            )
+        } else {
+            // Standard output is reserved for events, or for the command's output.
+            tracing::subscriber::set_global_default(builder.with_writer(std::io::stderr).finish())
+        };
+        installed.expect("tracing subscriber installed");
+        // The daemon writes to clients that may have gone away, so it needs SIGPIPE ignored too.
+        if !matches!(&self, App::Run(_) | App::Daemon(_)) {
+            reset_sigpipe();
+        }
         match self {
-            App::Run(args) => args.exec().await,
+            App::Run(args) => args.exec(progress).await,
+            App::Status(args) => args.exec().await,
+            App::Verify(args) => args.exec().await,
+            App::Clean(args) => args.exec().await,
+            App::Daemon(args) => args.exec().await,
+            App::Add(args) => args.exec().await,
+            App::Ls(args) => args.exec().await,
+            App::Pause(args) => args.pause().await,
+            App::Resume(args) => args.resume().await,
+            App::Cancel(args) => args.cancel().await,
+            App::Watch(args) => args.exec(progress).await,
         }
     }
 }
 
+/// Restores the default action for SIGPIPE, which is to terminate the process.
+///
+/// Rust ignores SIGPIPE, so that writing to a closed pipe returns an error instead -- and
+/// `println!` panics on that error. Commands that just print a report are more useful if they exit
+/// quietly like other Unix tools, e.g. in `download-manager status | head`.
+fn reset_sigpipe() {
+    // SAFETY: setting the disposition of SIGPIPE to SIG_DFL has no memory safety preconditions.
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
+}
+
 #[derive(Debug, Args)]
 pub struct DownloadArgs {
-    /// The download manifest
+    /// The download manifest, or `-` to read it from standard input
     #[clap(value_name = "PATH")]
     manifest: Utf8PathBuf,
 
+    /// The format of the manifest [default: detected from the extension, or TOML]
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    manifest_format: Option<ManifestFormat>,
+
+    #[clap(flatten)]
+    worker: WorkerArgs,
+
+    /// What to do when SIGHUP is received
+    #[clap(long, value_enum, value_name = "ACTION", default_value_t)]
+    on_sighup: HangupAction,
+
+    /// How to report progress
+    ///
+    /// With `json`, newline-delimited JSON events are written to standard output and logs to
+    /// standard error. See the `events` module for the schema.
+    #[clap(long, value_enum, value_name = "FORMAT", default_value_t)]
+    message_format: MessageFormat,
+}
+
+/// Options for how files are downloaded, shared by `run` and `daemon`.
+#[derive(Debug, Args)]
+struct WorkerArgs {
     /// The output directory to download to [default: current directory]
     #[clap(long, short = 'd', value_name = "DIR", default_value = "out")]
     out_dir: Utf8PathBuf,
-}
 
-impl DownloadArgs {
-    async fn exec(self) -> Result<()> {
-        tracing::debug!(manifest = %self.manifest);
+    /// How long to wait for downloads to stop after a signal, before exiting anyway
+    #[clap(long, value_name = "DURATION", default_value = "30s")]
+    shutdown_timeout: humantime::Duration,
+
+    /// The maximum number of downloads to run at the same time
+    #[clap(long, short = 'j', value_name = "N", default_value = "8")]
+    jobs: NonZeroUsize,
+
+    /// The maximum number of downloads to run at the same time from a single host [default: no
+    /// limit other than --jobs]
+    ///
+    /// This can be overridden per host with `max_connections` in the manifest's `[hosts]` table.
+    #[clap(long, value_name = "N")]
+    max_per_host: Option<NonZeroUsize>,
+
+    /// Fail a download if no data is received for this long (can be overridden per entry)
+    #[clap(long, value_name = "DURATION", default_value = "60s")]
+    idle_timeout: humantime::Duration,
+
+    /// Fail a download if it takes longer than this in total (can be overridden per entry)
+    #[clap(long, value_name = "DURATION")]
+    timeout: Option<humantime::Duration>,
+
+    /// The number of times to retry a download after a transient error (can be overridden per
+    /// entry)
+    ///
+    /// Connection errors, idle timeouts, 5xx responses and 429 Too Many Requests are retried with
+    /// exponential backoff. Other errors fail immediately.
+    #[clap(long, value_name = "N", default_value = "3")]
+    retries: u32,
+
+    /// Download files again even if a previous run already completed them
+    ///
+    /// By default, an entry is skipped if the database records it as completed, and the file on
+    /// disk still has the recorded size and matches any checksums in the manifest.
+    #[clap(long)]
+    force: bool,
 
-        // Load the manifest.
-        let manifest = Manifest::load(&self.manifest).await.map_err(|error| {
-            tracing::error!(error = %error, "Failed to load manifest");
-            error
-        })?;
+    /// The number of connections to download each file over (can be overridden per entry)
+    ///
+    /// With more than one, files are split into segments that are downloaded in parallel, if the
+    /// server supports range requests. Each file still counts as a single download towards --jobs
+    /// and --max-per-host.
+    #[clap(long, value_name = "N", default_value = "1")]
+    segments: NonZeroUsize,
 
+    /// A header to send with every request, in the form `Name: value` (can be passed multiple
+    /// times)
+    ///
+    /// Headers set in the manifest, per host or per entry, take precedence.
+    #[clap(long = "header", short = 'H', value_name = "HEADER", value_parser = headers::parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// The User-Agent header to send with requests
+    #[clap(long, value_name = "AGENT", default_value = DEFAULT_USER_AGENT)]
+    user_agent: String,
+
+    /// The maximum total rate to download at, in bytes per second (e.g. 500K or 2M) [default: no
+    /// limit]
+    ///
+    /// The limit is shared evenly between running downloads. Entries can set a lower limit of
+    /// their own with `limit_rate`. While running, SIGUSR1 halves the limit and SIGUSR2 doubles it.
+    #[clap(long, value_name = "RATE")]
+    limit_rate: Option<Rate>,
+}
+
+impl WorkerArgs {
+    /// Returns the options for manifest entries that set them neither themselves nor through
+    /// `[defaults]`.
+    fn fallback(&self) -> FallbackOptions {
+        FallbackOptions {
+            idle_timeout: *self.idle_timeout,
+            timeout: self.timeout.map(|timeout| *timeout),
+            retries: self.retries,
+            segments: self.segments,
+        }
+    }
+
+    /// Creates the output directory and starts the database task, returning the context that
+    /// workers are spawned with along with a handle to the database task.
+    ///
+    /// `hosts` is the manifest's `[hosts]` table.
+    async fn context(
+        &self,
+        hosts: &[(HostPattern, HostConfig)],
+        progress: Progress,
+        events: Events,
+    ) -> Result<(WorkerContext, JoinHandle<()>)> {
         // Create the output directory if it doesn't exist.
         fs_err::tokio::create_dir_all(&self.out_dir).await?;
         let out_dir = self.out_dir.canonicalize_utf8()?;
 
-        // Start a task tracking the database.
-        let (db_task, db_handle) = DatabaseTask::new();
+        // Start a task tracking the database. State from previous runs is loaded from the output
+        // directory.
+        let (db_task, db_handle) = DatabaseTask::load(&out_dir, events.clone()).await?;
         let db_task_handle = tokio::spawn(async move { db_task.run().await });
 
+        let host_limits = hosts
+            .iter()
+            .filter_map(|(pattern, config)| Some((pattern.clone(), config.max_connections?)))
+            .collect();
+        let host_headers = hosts
+            .iter()
+            .filter(|(_, config)| !config.headers.is_empty())
+            .map(|(pattern, config)| (pattern.clone(), config.headers.clone()))
+            .collect();
+        // Headers from the command line are sent by default, unless the manifest overrides them.
+        let client = reqwest::Client::builder()
+            .user_agent(&self.user_agent)
+            .default_headers(self.headers.iter().cloned().collect())
+            .build()?;
+        let ctx = WorkerContext {
+            client,
+            db_handle,
+            out_dir,
+            limiter: Arc::new(Limiter::new(self.jobs, self.max_per_host, host_limits)),
+            host_headers: Arc::new(host_headers),
+            rate_limit: Arc::new(RateLimiter::new(self.limit_rate)),
+            bytes_received: Arc::new(AtomicU64::new(0)),
+            force: self.force,
+            progress,
+            events,
+        };
+        Ok((ctx, db_task_handle))
+    }
+}
+
+/// The User-Agent header sent by default.
+const DEFAULT_USER_AGENT: &str = concat!("download-manager/", env!("CARGO_PKG_VERSION"));
+
+/// The action to take on SIGHUP.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum HangupAction {
+    /// Shut down gracefully, as with SIGINT and SIGTERM.
+    #[default]
+    Shutdown,
+    /// Reload the manifest and start downloading any new entries.
+    Reload,
+}
+
+/// Broadcasts a cancellation to all workers.
+///
+/// If this is the first shutdown signal, this also records its kind and starts the shutdown timer.
+fn shutdown(
+    sender: &broadcast::Sender<WorkerMessage>,
+    shutting_down: &mut Option<CancelKind>,
+    kind: CancelKind,
+    shutdown_timer: Pin<&mut Sleep>,
+    shutdown_timeout: Duration,
+) {
+    if shutting_down.is_none() {
+        *shutting_down = Some(kind);
+        shutdown_timer.reset(Instant::now() + shutdown_timeout);
+    }
+    // An error here just means that all the workers have already exited.
+    _ = sender.send(WorkerMessage::Cancel(kind));
+}
+
+/// How long to wait for workers to save their state before stopping the process on SIGTSTP.
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Asks all workers to pause, waits (for a bounded time) for them to save their state, and then
+/// writes it to disk.
+async fn pause_workers(sender: &broadcast::Sender<WorkerMessage>, db_handle: &DbWorkerHandle) {
+    let (ack_sender, mut ack_receiver) = mpsc::unbounded_channel();
+    let Ok(count) = sender.send(WorkerMessage::Pause(ack_sender)) else {
+        // There are no workers running.
+        return;
+    };
+
+    let wait_for_acks = async {
+        for _ in 0..count {
+            ack_receiver.recv().await;
+        }
+    };
+    if tokio::time::timeout(PAUSE_TIMEOUT, wait_for_acks)
+        .await
+        .is_err()
+    {
+        // This can happen if a worker is still waiting for the server to respond. It'll pause
+        // once the process is continued, right before resuming.
+        tracing::warn!("Some downloads didn't pause in time, stopping anyway");
+    }
+
+    // The database batches writes, so without this the paused state might only be written once
+    // the process is continued -- or never, if it's killed while it's stopped.
+    if let Err(error) = db_handle.flush().await {
+        tracing::error!(error = %error, "Failed to save state before stopping");
+    }
+}
+
+/// Stops the current process with SIGSTOP. This returns once the process is continued.
+fn stop_process() {
+    // SAFETY: raise has no memory safety preconditions.
+    let ret = unsafe { libc::raise(libc::SIGSTOP) };
+    if ret != 0 {
+        tracing::error!(error = %std::io::Error::last_os_error(), "Failed to stop process");
+    }
+}
+
+/// Scales the global rate limit by `factor`, in response to `signal`.
+fn adjust_rate_limit(rate_limit: &RateLimiter, signal: &str, factor: f64) -> SignalAction {
+    match rate_limit.rate() {
+        Some(rate) => {
+            let rate = rate.scale(factor);
+            tracing::info!(limit_rate = %rate, "{signal} received, changing rate limit");
+            rate_limit.set_rate(Some(rate));
+            SignalAction::ChangeRateLimit
+        }
+        None => {
+            tracing::warn!(
+                "{signal} received, but there's no rate limit to change (see --limit-rate)"
+            );
+            SignalAction::Ignore
+        }
+    }
+}
+
+/// Tracks the downloads scheduled in this run.
+#[derive(Debug, Default)]
+struct Scheduled {
+    /// Every URL scheduled in this run, so that duplicates (and entries that are already known when
+    /// reloading the manifest) aren't downloaded twice.
+    all: HashSet<Url>,
+    /// URLs whose workers haven't returned yet, along with their rate limiters.
+    running: BTreeMap<Url, Arc<RateLimiter>>,
+}
+
+/// Shared state needed to spawn workers.
+#[derive(Clone, Debug)]
+struct WorkerContext {
+    client: reqwest::Client,
+    db_handle: DbWorkerHandle,
+    out_dir: Utf8PathBuf,
+    limiter: Arc<Limiter>,
+    /// Headers from the manifest's `[hosts]` table, ordered from the least to the most specific
+    /// pattern.
+    host_headers: Arc<Vec<(HostPattern, HeaderMap)>>,
+    /// The rate limiter shared by all downloads.
+    rate_limit: Arc<RateLimiter>,
+    /// The total number of bytes received by all workers in this run.
+    bytes_received: Arc<AtomicU64>,
+    /// Whether to download files that a previous run already completed.
+    force: bool,
+    progress: Progress,
+    events: Events,
+}
+
+/// Timeouts for a single download.
+#[derive(Clone, Copy, Debug)]
+struct Timeouts {
+    /// The maximum time to wait for the server to send headers or the next chunk of data.
+    idle: Duration,
+    /// The maximum time the download can take overall, not counting time spent paused.
+    total: Option<Duration>,
+}
+
+/// The overall deadline for a download, shared by all of its attempts.
+#[derive(Clone, Copy, Debug)]
+struct Deadline {
+    at: Instant,
+    /// The timeout the deadline was set from.
+    total: Duration,
+}
+
+impl Deadline {
+    /// Returns the deadline for a download starting now, if it has a total timeout.
+    fn start(timeouts: Timeouts) -> Option<Self> {
+        let total = timeouts.total?;
+        Some(Self {
+            at: Instant::now() + total,
+            total,
+        })
+    }
+
+    /// Returns a timer that fires at `deadline`, or never if there isn't one.
+    fn timer(deadline: Option<Self>) -> Sleep {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.at),
+            None => tokio::time::sleep(Duration::MAX),
+        }
+    }
+
+    /// Runs `fut`, failing with [`DownloadError::DeadlineExceeded`] if `deadline` passes first.
+    async fn run<T>(deadline: Option<Self>, fut: impl Future<Output = Result<T>>) -> Result<T> {
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.at, fut)
+                .await
+                .map_err(|_| deadline.exceeded())?,
+            None => fut.await,
+        }
+    }
+
+    /// Waits for `delay` before retrying, or only until `deadline` if that's sooner.
+    ///
+    /// Fails with [`DownloadError::DeadlineExceeded`] if the deadline passes, since there's no time
+    /// left for another attempt.
+    async fn wait_to_retry(deadline: Option<Self>, delay: Duration) -> Result<()> {
+        let retry_at = Instant::now() + delay;
+        match deadline {
+            Some(deadline) if deadline.at <= retry_at => {
+                tokio::time::sleep_until(deadline.at).await;
+                Err(deadline.exceeded().into())
+            }
+            _ => {
+                tokio::time::sleep_until(retry_at).await;
+                Ok(())
+            }
+        }
+    }
+
+    fn exceeded(self) -> DownloadError {
+        DownloadError::DeadlineExceeded(self.total)
+    }
+}
+
+/// Per-download settings, taken from the manifest entry.
+#[derive(Clone, Debug)]
+struct DownloadOptions {
+    timeouts: Timeouts,
+    /// The number of times to retry transient errors.
+    retries: u32,
+    /// Checksums the downloaded file must match.
+    checksums: Vec<Checksum>,
+    /// The maximum number of connections to download the file over.
+    segments: NonZeroUsize,
+    /// Other URLs serving the same file, tried in order if downloading from the main URL fails.
+    mirrors: Vec<Url>,
+    /// Headers to send with every request for this download, inherited from `[defaults]`.
+    default_headers: HeaderMap,
+    /// Headers to send with every request for this download, set on the entry itself.
+    headers: HeaderMap,
+    /// The download's own rate limiter.
+    rate_limit: Arc<RateLimiter>,
+}
+
+/// Where a download attempt fetches data from, where it writes it to, and when it must finish by.
+#[derive(Debug)]
+struct Transfer {
+    /// The mirror to fetch data from, or `None` to use the download's own URL.
+    mirror: Option<Url>,
+    path: Utf8PathBuf,
+    temp_path: Utf8PathBuf,
+    /// The download's overall deadline, if it has one. The attempt pushes it back by however long
+    /// it's paused for.
+    deadline: Option<Deadline>,
+}
+
+impl Transfer {
+    /// Returns the URL to fetch data from.
+    fn source<'a>(&'a self, url: &'a Url) -> &'a Url {
+        self.mirror.as_ref().unwrap_or(url)
+    }
+}
+
+impl WorkerContext {
+    /// Returns the headers to send with requests to `source`.
+    ///
+    /// In increasing order of precedence, headers come from the manifest's `[defaults]`, the host
+    /// and the entry. (Headers from the command line are sent by the client, unless they're
+    /// overridden here.)
+    fn request_headers(&self, source: &Url, options: &DownloadOptions) -> HeaderMap {
+        let mut headers = options.default_headers.clone();
+        if let Some(host) = source.host_str() {
+            for (pattern, host_headers) in self.host_headers.iter() {
+                if pattern.matches(host) {
+                    headers.extend(host_headers.clone());
+                }
+            }
+        }
+        headers.extend(options.headers.clone());
+        headers
+    }
+
+    /// Returns the rate limiters that apply to a download.
+    fn throttle(&self, options: &DownloadOptions) -> Throttle {
+        Throttle {
+            global: self.rate_limit.clone(),
+            download: options.rate_limit.clone(),
+        }
+    }
+
+    /// Spawns a worker for `entry` onto `join_set`, unless its URL has already been scheduled.
+    ///
+    /// If the download is already running (e.g. because the manifest was reloaded), its rate limit
+    /// is updated instead.
+    ///
+    /// Returns false if the entry's URL was already scheduled.
+    fn spawn(
+        &self,
+        join_set: &mut JoinSet<WorkerOutput>,
+        scheduled: &mut Scheduled,
+        entry: ManifestEntry,
+        sender: &broadcast::Sender<WorkerMessage>,
+    ) -> bool {
+        if !scheduled.all.insert(entry.url.clone()) {
+            if let Some(rate_limit) = scheduled.running.get(&entry.url) {
+                if rate_limit.rate() != entry.limit_rate {
+                    tracing::info!(
+                        url = %entry.url,
+                        limit_rate = entry.limit_rate.map(tracing::field::display),
+                        "Updating rate limit",
+                    );
+                    rate_limit.set_rate(entry.limit_rate);
+                }
+            }
+            tracing::debug!(url = %entry.url, "Download already scheduled, skipping");
+            return false;
+        }
+        let rate_limit = Arc::new(RateLimiter::new(entry.limit_rate));
+        scheduled
+            .running
+            .insert(entry.url.clone(), rate_limit.clone());
+        join_set.spawn(worker_fn(
+            self.clone(),
+            entry,
+            rate_limit,
+            sender.subscribe(),
+        ));
+        true
+    }
+}
+
+impl DownloadArgs {
+    async fn exec(self, progress: Progress) -> Result<ExitCode> {
+        tracing::debug!(manifest = %self.manifest);
+        let start = Instant::now();
+
+        // Load the manifest. Options that entries don't set, either themselves or through
+        // `[defaults]`, are taken from the command line.
+        let fallback = self.worker.fallback();
+        let manifest = Manifest::load(&self.manifest, self.manifest_format, &fallback)
+            .await
+            .map_err(|error| {
+                tracing::error!(error = %error, "Failed to load manifest");
+                error
+            })?;
+
+        // Set up the output directory and the database, and everything else workers share.
+        let events = Events::new(self.message_format);
+        let (ctx, db_task_handle) = self
+            .worker
+            .context(&manifest.hosts, progress, events)
+            .await?;
+
         tracing::info!("Downloading {} files", manifest.downloads.len());
 
         // Create a JoinSet to track currently downloading tasks.
-        let mut join_set = tokio::task::JoinSet::new();
+        let mut join_set = JoinSet::new();
 
         // Create a channel to send signals.
         let (sender, _) = broadcast::channel(16);
 
-        // Start the SIGINT signal handler.
-        //
-        // TODO/exercise (easy): In a real application you'll likely want to handle more signals
-        // than just Ctrl-C. Try implementing support for SIGTERM and SIGHUP.
-        //
-        // TODO/exercise (hard): As a stretch goal, implement support for SIGTSTP and SIGCONT that:
-        // - pauses timers when SIGTSTP is encountered, then stops the current process.
-        // - resumes timers when the process is resumed with SIGCONT.
+        // Start the signal handlers.
         //
-        // Some ideas to get you started:
+        // SIGINT and SIGTERM always shut down gracefully. SIGHUP either shuts down or reloads the
+        // manifest, depending on --on-sighup.
         //
-        // - Once you've paused timers you'll also want to stop the current process. How would you
-        //   do this? (Hint: look at man 7 signal for a signal similar to SIGTSTP.)
-        // - The libsw library might be of help: https://docs.rs/libsw
-        let mut ctrl_c_stream =
-            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())?;
+        // SIGTSTP (Ctrl-Z) pauses downloads and their timers, then stops the process with SIGSTOP.
+        // (Once a handler is installed for SIGTSTP, the process no longer stops by default, so we
+        // must do so ourselves.) SIGCONT resumes downloads.
+        let mut ctrl_c_stream = signal(SignalKind::interrupt())?;
+        let mut sigterm_stream = signal(SignalKind::terminate())?;
+        let mut sighup_stream = signal(SignalKind::hangup())?;
+        let mut sigtstp_stream = signal(SignalKind::from_raw(libc::SIGTSTP))?;
+        let mut sigcont_stream = signal(SignalKind::from_raw(libc::SIGCONT))?;
+        let mut sigusr1_stream = signal(SignalKind::user_defined1())?;
+        let mut sigusr2_stream = signal(SignalKind::user_defined2())?;
+        let mut sigwinch_stream = signal(SignalKind::window_change())?;
 
-        // Spawn tasks corresponding to each download.
-        //
-        // In a real application you'll want to use limiting here to ensure that downloads don't get
-        // scheduled.
-        let client = reqwest::Client::new();
+        // Spawn tasks corresponding to each download. Each task waits on the limiter before it
+        // starts downloading, so only a bounded number of downloads are in progress at a time.
+        let mut summary = RunSummary::default();
+        let mut scheduled = Scheduled::default();
         for entry in manifest.downloads {
-            let receiver = sender.subscribe();
-            join_set.spawn(worker_fn(
-                client.clone(),
-                db_handle.clone(),
-                entry,
-                out_dir.clone(),
-                receiver,
-            ));
+            let url = entry.url.clone();
+            if !ctx.spawn(&mut join_set, &mut scheduled, entry, &sender) {
+                // Only the first entry for each URL is downloaded. Duplicates aren't counted as
+                // skipped, since that's reserved for downloads a previous run completed.
+                tracing::warn!(url = %url, "Duplicate manifest entry, ignoring");
+            }
         }
 
-        // Close the database handle we're holding on to. That is a signal that no more downloads
-        // will be queued.
-        std::mem::drop(db_handle);
+        // If a signal has caused us to start shutting down, this is the kind of signal.
+        let mut shutting_down = None;
 
-        // This tracks which operations failed.
-        let mut failed = Vec::new();
+        // Once shutdown starts, this timer bounds how long we wait for workers to finish. It's
+        // reset when the first signal arrives, and isn't polled before that.
+        let shutdown_timer = tokio::time::sleep(Duration::ZERO);
+        let mut shutdown_timer = std::pin::pin!(shutdown_timer);
 
-        // Loop over a Tokio select with two branches:
-        loop {
+        // Loop over a Tokio select, with one branch for task completions, one for each signal, and
+        // one for the shutdown timer. The loop evaluates to true if we need to exit immediately.
+        let forced_exit = loop {
+            ctx.progress.set_files(
+                scheduled.all.len() - scheduled.running.len(),
+                scheduled.all.len(),
+            );
             tokio::select! {
                 v = join_set.join_next() => {
                     match v {
                         Some(Ok(output)) => {
-                            match output.result {
-                                Ok(WorkerStatus::Completed) => {
-                                    tracing::info!(url = %output.url, path = %output.path, "Download completed");
-                                }
-                                Ok(WorkerStatus::Cancelled) => {
-                                    tracing::warn!(url = %output.url, path = %output.path, "Download cancelled");
-                                }
-                                Err(error) => {
-                                    tracing::error!(error = %error, url = %output.url, path = %output.path, "Download failed");
-                                    failed.push(output.url);
-                                }
-                            }
                             // A download task finished successfully.
+                            scheduled.running.remove(&output.url);
+                            output.report(&ctx.events, &mut summary);
                         }
                         Some(Err(error)) => {
                             // A task panicked or was cancelled. In this demo we just log this
                             // error, but in production code you could e.g. cancel any pending
                             // downloads and exit if this occurs.
                             tracing::error!(error = %error, "Download task failed");
+                            summary.panicked += 1;
                         }
                         None => {
                             // All downloads completed, failed or interrupted.
-                            break;
+                            break false;
                         }
                     }
                 }
                 Some(_) = ctrl_c_stream.recv() => {
-                    tracing::info!("Ctrl-C received, terminating downloads");
-                    sender.send(CancelMessage::new(CancelKind::Interrupt))?;
+                    if shutting_down.is_some() {
+                        // This is the "double Ctrl-C" pattern: the first Ctrl-C asks workers to
+                        // stop, and the second one exits immediately.
+                        tracing::warn!("Ctrl-C received again, exiting immediately");
+                        ctx.events.emit(Event::SignalReceived { signal: "SIGINT", action: SignalAction::Exit });
+                        break true;
+                    }
+                    tracing::info!("Ctrl-C received, terminating downloads (press Ctrl-C again to exit immediately)");
+                    ctx.events.emit(Event::SignalReceived { signal: "SIGINT", action: SignalAction::Shutdown });
+                    shutdown(
+                        &sender,
+                        &mut shutting_down,
+                        CancelKind::Interrupt,
+                        shutdown_timer.as_mut(),
+                        *self.worker.shutdown_timeout,
+                    );
 
                     // Don't break here -- wait for all the downloads to finish.
-
-                    // TODO/exercise (medium): implement the "double ctrl-c" pattern. The first time
-                    // Ctrl-C is pressed, send a cancellation message and wait for worker tasks to
-                    // finish. The second time, exit immediately.
                 }
+                Some(_) = sigterm_stream.recv() => {
+                    tracing::info!("SIGTERM received, terminating downloads");
+                    ctx.events.emit(Event::SignalReceived { signal: "SIGTERM", action: SignalAction::Shutdown });
+                    shutdown(
+                        &sender,
+                        &mut shutting_down,
+                        CancelKind::Terminate,
+                        shutdown_timer.as_mut(),
+                        *self.worker.shutdown_timeout,
+                    );
+                }
+                Some(_) = sighup_stream.recv() => {
+                    match self.on_sighup {
+                        HangupAction::Shutdown => {
+                            tracing::info!("SIGHUP received, terminating downloads");
+                            ctx.events.emit(Event::SignalReceived {
+                                signal: "SIGHUP",
+                                action: SignalAction::Shutdown,
+                            });
+                            shutdown(
+                                &sender,
+                                &mut shutting_down,
+                                CancelKind::Hangup,
+                                shutdown_timer.as_mut(),
+                                *self.worker.shutdown_timeout,
+                            );
+                        }
+                        HangupAction::Reload if shutting_down.is_some() => {
+                            tracing::info!("SIGHUP received while shutting down, not reloading");
+                            ctx.events.emit(Event::SignalReceived {
+                                signal: "SIGHUP",
+                                action: SignalAction::Ignore,
+                            });
+                        }
+                        HangupAction::Reload if self.manifest == STDIN_PATH => {
+                            // Standard input has already been read to the end.
+                            tracing::warn!(
+                                "SIGHUP received, but the manifest was read from standard input \
+                                 and can't be reloaded"
+                            );
+                            ctx.events.emit(Event::SignalReceived {
+                                signal: "SIGHUP",
+                                action: SignalAction::Ignore,
+                            });
+                        }
+                        HangupAction::Reload => {
+                            tracing::info!("SIGHUP received, reloading manifest");
+                            ctx.events.emit(Event::SignalReceived {
+                                signal: "SIGHUP",
+                                action: SignalAction::Reload,
+                            });
+                            // Entries removed from the manifest keep downloading: only new entries
+                            // are picked up.
+                            match Manifest::load(&self.manifest, self.manifest_format, &fallback).await {
+                                Ok(manifest) => {
+                                    for entry in manifest.downloads {
+                                        ctx.spawn(&mut join_set, &mut scheduled, entry, &sender);
+                                    }
+                                }
+                                Err(error) => {
+                                    // Keep going with the downloads that are already running.
+                                    tracing::error!(error = %error, "Failed to reload manifest");
+                                }
+                            }
+                        }
+                    }
+                }
+                Some(_) = sigtstp_stream.recv() => {
+                    tracing::info!("SIGTSTP received, pausing downloads");
+                    ctx.events.emit(Event::SignalReceived { signal: "SIGTSTP", action: SignalAction::Pause });
+                    pause_workers(&sender, &ctx.db_handle).await;
+                    tracing::info!("Stopping process, run `fg` or send SIGCONT to resume");
+                    // Leave the terminal clean for the shell while we're stopped.
+                    ctx.progress.clear();
+                    stop_process();
+                }
+                Some(_) = sigcont_stream.recv() => {
+                    // This is also received after an external SIGSTOP, in which case the workers
+                    // weren't paused and just ignore this message.
+                    tracing::info!("SIGCONT received, resuming downloads");
+                    ctx.events.emit(Event::SignalReceived { signal: "SIGCONT", action: SignalAction::Resume });
+                    ctx.progress.redraw();
+                    _ = sender.send(WorkerMessage::Resume);
+                }
+                Some(_) = sigwinch_stream.recv() => {
+                    // The terminal was resized, so the bars need to be redrawn at the new width.
+                    ctx.progress.redraw();
+                }
+                Some(_) = sigusr1_stream.recv() => {
+                    let action = adjust_rate_limit(&ctx.rate_limit, "SIGUSR1", 0.5);
+                    ctx.events.emit(Event::SignalReceived { signal: "SIGUSR1", action });
+                }
+                Some(_) = sigusr2_stream.recv() => {
+                    let action = adjust_rate_limit(&ctx.rate_limit, "SIGUSR2", 2.0);
+                    ctx.events.emit(Event::SignalReceived { signal: "SIGUSR2", action });
+                }
+                () = &mut shutdown_timer, if shutting_down.is_some() => {
+                    tracing::warn!(
+                        timeout = %self.worker.shutdown_timeout,
+                        "Downloads didn't stop within the shutdown timeout, exiting immediately",
+                    );
+                    break true;
+                }
+            }
+        };
+
+        if forced_exit {
+            // Abort the remaining workers and wait for them to actually go away, so that they don't
+            // race with the database updates below.
+            join_set.shutdown().await;
+
+            let kind = shutting_down.expect("forced exits only happen after a shutdown signal");
+            for url in scheduled.running.keys() {
+                tracing::warn!(url = %url, "Download abandoned");
+                ctx.db_handle.mark_interrupted(url.clone(), kind).await?;
             }
+            tracing::error!(
+                abandoned = scheduled.running.len(),
+                "Exited before all downloads stopped; partial files may be incomplete",
+            );
+            summary.cancelled += scheduled.running.len();
         }
 
+        ctx.progress.finish();
+        summary.bytes = ctx.bytes_received.load(Ordering::Relaxed);
+        summary.elapsed = start.elapsed();
+        summary.report();
+        let exit_code = summary.exit_code(shutting_down, forced_exit);
+        ctx.events.emit(Event::Shutdown {
+            completed: summary.completed,
+            failed: summary.failed_count(),
+            cancelled: summary.cancelled,
+            skipped: summary.skipped,
+            bytes: summary.bytes,
+            elapsed_ms: summary.elapsed.as_millis() as u64,
+            signal: shutting_down.and_then(CancelKind::signal_name),
+            forced: forced_exit,
+            exit_code,
+        });
+
+        // Close the database handle we're holding on to. That is a signal that no more downloads
+        // will be queued.
+        std::mem::drop(ctx);
+
         // Wait for the database task to shut down. This is good hygiene but not strictly required.
         db_task_handle.await.wrap_err("database task panicked")?;
 
-        Ok(())
+        Ok(ExitCode::from(exit_code))
     }
 }
 
@@ -167,66 +863,276 @@ impl DownloadArgs {
 /// This function is responsible for downloading a particular file asynchronously. On completion, it returns
 /// the URL it downloaded, the path it downloaded to, and the result of the download.
 async fn worker_fn(
-    client: reqwest::Client,
-    db_handle: DbWorkerHandle,
+    ctx: WorkerContext,
     entry: ManifestEntry,
-    out_dir: Utf8PathBuf,
-    receiver: broadcast::Receiver<CancelMessage>,
+    rate_limit: Arc<RateLimiter>,
+    receiver: broadcast::Receiver<WorkerMessage>,
 ) -> WorkerOutput {
-    let path = entry.file_name.unwrap_or_else(|| {
-        entry
-            .url
-            .path_segments()
-            .and_then(|segments| segments.last())
-            .unwrap_or("index.html")
-            .to_string()
-    });
-    let out_path = out_dir.join(path);
-
-    let result = worker_impl(client, db_handle, entry.url.clone(), &out_path, receiver).await;
+    let out_path = entry.out_path(&ctx.out_dir);
+    let options = DownloadOptions {
+        timeouts: Timeouts {
+            idle: entry.idle_timeout,
+            total: entry.timeout,
+        },
+        retries: entry.retries,
+        checksums: entry.checksums,
+        segments: entry.segments,
+        mirrors: entry.mirrors,
+        default_headers: entry.default_headers,
+        headers: entry.headers,
+        rate_limit,
+    };
+
+    let result = worker_impl(ctx, entry.url.clone(), &out_path, &options, receiver).await;
 
     WorkerOutput {
         url: entry.url,
-        path: out_dir,
+        path: out_path,
         result,
     }
 }
 
 async fn worker_impl(
-    client: reqwest::Client,
-    db_handle: DbWorkerHandle,
+    ctx: WorkerContext,
     url: Url,
     out_path: &Utf8Path,
-    mut receiver: broadcast::Receiver<CancelMessage>,
+    options: &DownloadOptions,
+    mut receiver: broadcast::Receiver<WorkerMessage>,
 ) -> Result<WorkerStatus> {
-    // This channel is used to flush and cancel the download if it's in progress.
-    let (cancel_sender, cancel_receiver) = oneshot::channel();
-    // Put the cancel sender in a `Option` so that we can take it out in the select loop. If
-    // cancel_sender is Some, it means that the download hasn't been cancelled yet.
-    let mut cancel_sender = Some(cancel_sender);
+    // This channel is used to forward messages (cancel, pause and resume) to the download if it's
+    // in progress.
+    let (message_sender, mut message_receiver) = mpsc::unbounded_channel();
+    let WorkerContext {
+        db_handle,
+        out_dir,
+        limiter,
+        force,
+        ..
+    } = &ctx;
 
     // This is the operation that actually performs the download.
     let op = async {
-        db_handle
-            .update_state(url.clone(), DownloadState::Downloading)
-            .await?;
-        let res = download_url_to(client, url.clone(), out_path, cancel_receiver).await;
-        match res {
-            Ok(WorkerStatus::Completed) => {
-                db_handle
-                    .update_state(url.clone(), DownloadState::Completed)
-                    .await?;
+        // Look up the previous record before queueing the download, since queueing overwrites its
+        // state.
+        let previous = db_handle.get(url.clone()).await?;
+        if !*force && is_already_complete(previous.as_ref(), out_path, &options.checksums).await? {
+            return Ok(WorkerStatus::Skipped);
+        }
+
+        // The URLs to try: the download's own URL, followed by its mirrors. If a previous run got
+        // partway through downloading from a mirror, start with that one so the partial file can
+        // be resumed.
+        let sources: Vec<Option<&Url>> = std::iter::once(None)
+            .chain(options.mirrors.iter().map(Some))
+            .collect();
+        let mut source = previous
+            .as_ref()
+            .and_then(|previous| {
+                let mirror = previous.mirror.as_ref()?;
+                options.mirrors.iter().position(|m| m == mirror)
+            })
+            .map_or(0, |index| index + 1);
+        // The number of sources that have failed since the last retry.
+        let mut sources_failed = 0;
+
+        // Wait for a free slot before starting the download. Cancellations must be handled here
+        // too, since a download can spend a long time in the queue.
+        db_handle.queue(url.clone(), out_path.to_owned()).await?;
+        let first_source = sources[source].unwrap_or(&url);
+        let mut permits =
+            match wait_or_cancel(limiter.acquire(first_source), &mut message_receiver).await {
+                Ok(permits) => permits,
+                Err(kind) => {
+                    db_handle.mark_interrupted(url.clone(), kind).await?;
+                    return Ok(WorkerStatus::Cancelled(kind));
+                }
+            };
+
+        // The entry may be downloaded into a subdirectory of the output directory.
+        if let Some(parent) = out_path.parent() {
+            fs_err::tokio::create_dir_all(parent).await?;
+        }
+
+        // Show a progress bar while the download is active. It's removed when this is dropped.
+        let progress = ctx.progress.add(out_path);
+
+        // The deadline covers every attempt, including time spent sending requests and waiting to
+        // retry. Attempts push it back by however long they're paused for.
+        let mut deadline = Deadline::start(options.timeouts);
+
+        let temp_path = part_path(out_path);
+        let mut previous = previous;
+        let mut attempt = 1;
+        let mut retry = 0;
+        let res = loop {
+            let mut transfer = Transfer {
+                mirror: sources[source].cloned(),
+                path: out_path.to_owned(),
+                temp_path: temp_path.clone(),
+                deadline,
+            };
+
+            // Per-host limits apply to the host that data is actually fetched from, so switching to
+            // a mirror on another host means waiting for a slot there.
+            let switch = limiter.switch(&mut permits, transfer.source(&url));
+            if let Err(kind) = wait_or_cancel(switch, &mut message_receiver).await {
+                db_handle.mark_interrupted(url.clone(), kind).await?;
+                return Ok(WorkerStatus::Cancelled(kind));
             }
-            Ok(WorkerStatus::Cancelled) => {
+
+            // If a previous run (or attempt) left a partial file behind, try to pick up where it
+            // left off.
+            let resume = resume_point(previous, &transfer, !options.checksums.is_empty()).await?;
+            let resume_from = resume.as_ref().map_or(0, |resume| resume.offset);
+
+            db_handle
+                .start(
+                    url.clone(),
+                    out_path.to_owned(),
+                    temp_path.clone(),
+                    resume_from,
+                    attempt,
+                    transfer.mirror.clone(),
+                )
+                .await?;
+            ctx.events.emit(Event::Started {
+                url: &url,
+                path: out_path,
+                attempt,
+                resume_from,
+                mirror: transfer.mirror.as_ref(),
+            });
+            let res = download_url_to(
+                &ctx,
+                url.clone(),
+                &mut transfer,
+                resume,
+                options,
+                &progress,
+                &mut message_receiver,
+            )
+            .await;
+            deadline = transfer.deadline;
+            let Err(error) = &res else {
+                break res;
+            };
+
+            // If another mirror might work, move on to it straight away.
+            if is_source_error(error) && sources_failed + 1 < sources.len() {
+                sources_failed += 1;
+                source = (source + 1) % sources.len();
+                tracing::warn!(
+                    url = %url,
+                    source = %transfer.source(&url),
+                    error = %error,
+                    "Download failed, trying next mirror: {}",
+                    sources[source].unwrap_or(&url),
+                );
+                ctx.events.emit(Event::Retry {
+                    url: &url,
+                    attempt,
+                    error: &format!("{error:#}"),
+                    delay_ms: 0,
+                    next_source: sources[source].unwrap_or(&url),
+                });
                 db_handle
-                    .update_state(url.clone(), DownloadState::Interrupted)
+                    .mark_failed(url.clone(), DownloadState::Retrying, format!("{error:#}"))
                     .await?;
+                previous = db_handle.get(url.clone()).await?;
+                attempt += 1;
+                continue;
+            }
+
+            // Retry transient errors.
+            if retry >= options.retries {
+                break res;
             }
-            Err(_) => {
+            let ErrorClass::Transient { retry_after } = classify_error(error) else {
+                break res;
+            };
+            retry += 1;
+            let delay = retry_delay(retry, retry_after);
+            tracing::warn!(
+                url = %url,
+                error = %error,
+                attempt,
+                "Download failed, retrying in {}",
+                humantime::format_duration(delay),
+            );
+            // The next round of attempts starts with the next source. (Without mirrors, that's the
+            // same one.)
+            source = (source + 1) % sources.len();
+            ctx.events.emit(Event::Retry {
+                url: &url,
+                attempt,
+                error: &format!("{error:#}"),
+                delay_ms: delay.as_millis() as u64,
+                next_source: sources[source].unwrap_or(&url),
+            });
+            db_handle
+                .mark_failed(url.clone(), DownloadState::Retrying, format!("{error:#}"))
+                .await?;
+
+            // Wait before retrying. Cancellation signals interrupt the wait immediately. If the
+            // deadline passes first, the download times out without another attempt.
+            match wait_or_cancel(
+                Deadline::wait_to_retry(deadline, delay),
+                &mut message_receiver,
+            )
+            .await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => break Err(error),
+                Err(kind) => {
+                    db_handle.mark_interrupted(url.clone(), kind).await?;
+                    return Ok(WorkerStatus::Cancelled(kind));
+                }
+            }
+
+            sources_failed = 0;
+            previous = db_handle.get(url.clone()).await?;
+            attempt += 1;
+        };
+
+        match &res {
+            Ok(WorkerStatus::Completed { .. }) => {
                 db_handle
-                    .update_state(url.clone(), DownloadState::Failed)
+                    .complete(url.clone(), options.checksums.clone())
                     .await?;
             }
+            Ok(WorkerStatus::Skipped) => unreachable!("downloads are only skipped before starting"),
+            Ok(WorkerStatus::Cancelled(kind)) => {
+                db_handle.mark_interrupted(url.clone(), *kind).await?;
+            }
+            Err(error) => match error.downcast_ref::<DownloadError>() {
+                Some(DownloadError::ChecksumMismatch(_)) => {
+                    // Move the file out of the way, so nothing mistakes it for a good download.
+                    let quarantine_path = quarantine(out_dir, &temp_path, out_path).await?;
+                    tracing::warn!(
+                        url = %url,
+                        path = %quarantine_path,
+                        "Checksum mismatch, moved file to quarantine",
+                    );
+                    db_handle
+                        .mark_failed(
+                            url.clone(),
+                            DownloadState::Corrupt,
+                            format!("{error:#} (file moved to `{quarantine_path}`)"),
+                        )
+                        .await?;
+                }
+                other => {
+                    let state = match other {
+                        Some(
+                            DownloadError::IdleTimeout(_) | DownloadError::DeadlineExceeded(_),
+                        ) => DownloadState::TimedOut,
+                        _ => DownloadState::Failed,
+                    };
+                    db_handle
+                        .mark_failed(url.clone(), state, format!("{error:#}"))
+                        .await?;
+                }
+            },
         }
 
         res
@@ -241,85 +1147,729 @@ async fn worker_impl(
                 // The download completed, or failed.
                 return res;
             }
-            // A cancellation signal was received.
-            Ok(_) = receiver.recv() => {
-                // If we haven't already cancelled the download, do so now.
-                if let Some(sender) = cancel_sender.take() {
-                    _ = sender.send(());
+            // A message was received from the main loop.
+            Ok(message) = receiver.recv() => {
+                // Forward it to the download. If it's a cancellation, this will cause op to exit
+                // soon -- loop until that happens.
+                _ = message_sender.send(message);
+            }
+        }
+    }
+}
+
+/// The directory within the output directory that corrupt downloads are moved to.
+pub(crate) const QUARANTINE_DIR: &str = ".quarantine";
+
+/// Moves a corrupt download at `temp_path` into the quarantine directory, returning its new path.
+///
+/// Within the quarantine directory, the file has the same relative path as `out_path`, the path it
+/// would have been moved to had it been valid. That way, files with the same name in different
+/// directories don't overwrite each other.
+async fn quarantine(
+    out_dir: &Utf8Path,
+    temp_path: &Utf8Path,
+    out_path: &Utf8Path,
+) -> Result<Utf8PathBuf> {
+    let relative_path = out_path
+        .strip_prefix(out_dir)
+        .expect("download paths are always within the output directory");
+    let quarantine_path = out_dir.join(QUARANTINE_DIR).join(relative_path);
+    if let Some(parent) = quarantine_path.parent() {
+        fs_err::tokio::create_dir_all(parent).await?;
+    }
+    fs_err::tokio::rename(temp_path, &quarantine_path).await?;
+    Ok(quarantine_path)
+}
+
+/// The extension appended to a download's path to get its temporary path.
+pub(crate) const PART_EXTENSION: &str = "part";
+
+/// Returns the temporary path that data for `out_path` is written to while it's downloading.
+pub(crate) fn part_path(out_path: &Utf8Path) -> Utf8PathBuf {
+    format!("{out_path}.{PART_EXTENSION}").into()
+}
+
+/// Waits for `fut` to complete, while handling messages from the main loop.
+///
+/// This is used while no transfer is in progress (e.g. while queued or waiting to retry), so there's
+/// nothing to save when asked to pause. While paused, `fut` isn't polled, so a queued download that
+/// the daemon was asked to pause doesn't start until it's resumed. Returns `Err` with the kind of
+/// cancellation if the download is cancelled first.
+async fn wait_or_cancel<F: Future>(
+    fut: F,
+    messages: &mut mpsc::UnboundedReceiver<WorkerMessage>,
+) -> Result<F::Output, CancelKind> {
+    let mut fut = std::pin::pin!(fut);
+    let mut paused = false;
+    loop {
+        tokio::select! {
+            output = &mut fut, if !paused => return Ok(output),
+            Some(message) = messages.recv() => {
+                match message {
+                    WorkerMessage::Cancel(kind) => return Err(kind),
+                    WorkerMessage::Pause(ack) => {
+                        paused = true;
+                        _ = ack.send(());
+                    }
+                    WorkerMessage::Resume => paused = false,
                 }
+            }
+        }
+    }
+}
+
+/// Whether an error is worth retrying.
+#[derive(Debug)]
+enum ErrorClass {
+    /// The error is likely transient, e.g. a dropped connection or an overloaded server.
+    Transient {
+        /// How long the server asked us to wait before retrying, if it did.
+        retry_after: Option<Duration>,
+    },
+    /// The error will likely happen again, e.g. a 404 or a disk error.
+    Fatal,
+}
 
-                // This will cause op to exit soon -- loop until that happens.
+fn classify_error(error: &eyre::Report) -> ErrorClass {
+    if let Some(error) = error.downcast_ref::<DownloadError>() {
+        return match error {
+            // A segmented download that hit RangeIgnored throws away its progress, so a retry
+            // starts over with a fresh probe. A truncated segment is resumed where it left off.
+            DownloadError::IdleTimeout(_)
+            | DownloadError::RangeIgnored
+            | DownloadError::SegmentTruncated(_) => ErrorClass::Transient { retry_after: None },
+            DownloadError::DeadlineExceeded(_) | DownloadError::ChecksumMismatch(_) => {
+                ErrorClass::Fatal
             }
+            DownloadError::HttpStatus {
+                status,
+                retry_after,
+            } => {
+                if status.is_server_error()
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+                {
+                    ErrorClass::Transient {
+                        retry_after: *retry_after,
+                    }
+                } else {
+                    ErrorClass::Fatal
+                }
+            }
+        };
+    }
+
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        // Failures to connect, and connections that were reset or timed out while sending the
+        // request or receiving the body.
+        if error.is_connect() || error.is_timeout() || error.is_request() || error.is_body() {
+            return ErrorClass::Transient { retry_after: None };
+        }
+    }
+
+    // Everything else, including I/O errors writing to disk.
+    ErrorClass::Fatal
+}
+
+/// Returns true if `error` is specific to the server being downloaded from, so that a mirror might
+/// not run into it.
+fn is_source_error(error: &eyre::Report) -> bool {
+    match error.downcast_ref::<DownloadError>() {
+        Some(
+            DownloadError::IdleTimeout(_)
+            | DownloadError::HttpStatus { .. }
+            | DownloadError::RangeIgnored
+            | DownloadError::SegmentTruncated(_),
+        ) => true,
+        Some(DownloadError::DeadlineExceeded(_) | DownloadError::ChecksumMismatch(_)) => false,
+        None => error.downcast_ref::<reqwest::Error>().is_some(),
+    }
+}
+
+/// The delay before the first retry. This doubles with each attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// The maximum delay between retries.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Returns how long to wait before retrying after `attempt` failed.
+///
+/// This uses exponential backoff, randomly reduced by up to half so that many downloads failing at
+/// once don't all retry at the same instant. A `Retry-After` from the server takes precedence, but
+/// is capped at `RETRY_MAX_DELAY` too, so that a server can't hold up a download (and the slots it
+/// holds) for hours.
+fn retry_delay(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    if let Some(retry_after) = retry_after {
+        return retry_after.min(RETRY_MAX_DELAY);
+    }
+    let delay = RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(RETRY_MAX_DELAY);
+    let delay = delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+    // Round to milliseconds to keep log output readable.
+    Duration::from_millis(delay.as_millis() as u64)
+}
+
+/// Returns true if a previous run completed this download, and the file on disk still looks the
+/// same.
+///
+/// The file must have the size recorded in the database. Checksums in the manifest that the file
+/// wasn't verified against when it was downloaded (e.g. because they were added to the manifest
+/// later) are checked by hashing the file.
+async fn is_already_complete(
+    previous: Option<&DownloadRecord>,
+    out_path: &Utf8Path,
+    checksums: &[Checksum],
+) -> Result<bool> {
+    let Some(previous) = previous else {
+        return Ok(false);
+    };
+    if previous.state != DownloadState::Completed || previous.path != out_path {
+        return Ok(false);
+    }
+
+    let len = match fs_err::tokio::metadata(out_path).await {
+        Ok(metadata) => metadata.len(),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error.into()),
+    };
+    if len != previous.bytes_downloaded {
+        tracing::info!(
+            path = %out_path,
+            expected = previous.bytes_downloaded,
+            actual = len,
+            "File size changed since it was downloaded, downloading again",
+        );
+        return Ok(false);
+    }
+
+    let unverified: Vec<_> = checksums
+        .iter()
+        .filter(|checksum| previous.checksums.get(&checksum.algorithm) != Some(&checksum.expected))
+        .cloned()
+        .collect();
+    if unverified.is_empty() {
+        return Ok(true);
+    }
+
+    let verifier = Verifier::new(&unverified)
+        .update_from_file(out_path, len)
+        .await?;
+    match verifier.verify() {
+        Ok(()) => Ok(true),
+        Err(error) => {
+            tracing::info!(
+                path = %out_path,
+                error = %error,
+                "Existing file doesn't match checksum, downloading again",
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// Where to resume a partial download from.
+#[derive(Debug)]
+struct ResumePoint {
+    /// The number of bytes already in the temporary file.
+    offset: u64,
+    /// The value to send in the `If-Range` header, or `None` to resume without checking that the
+    /// file hasn't changed.
+    if_range: Option<String>,
+    /// For segmented downloads, the progress of each segment. Empty otherwise.
+    segments: Vec<Segment>,
+}
+
+/// Determines whether a download can be resumed, based on the previous database record and the
+/// temporary file on disk.
+///
+/// `verified` indicates whether the download will be checked against checksums once it's complete.
+async fn resume_point(
+    previous: Option<DownloadRecord>,
+    transfer: &Transfer,
+    verified: bool,
+) -> Result<Option<ResumePoint>> {
+    let Transfer {
+        mirror,
+        path: out_path,
+        temp_path,
+        ..
+    } = transfer;
+    let Some(previous) = previous else {
+        return Ok(None);
+    };
+    match previous.state {
+        DownloadState::Interrupted
+        | DownloadState::Failed
+        | DownloadState::TimedOut
+        | DownloadState::Retrying
+        | DownloadState::Paused => {}
+        // A corrupt file has been moved to quarantine, so it must be downloaded from scratch.
+        DownloadState::Queued
+        | DownloadState::Downloading
+        | DownloadState::Completed
+        | DownloadState::Corrupt => return Ok(None),
+    }
+    if previous.path != *out_path || previous.temp_path.as_ref() != Some(temp_path) {
+        return Ok(None);
+    }
+    let if_range = if previous.mirror == *mirror {
+        // Without a validator, there's no way to tell whether the file on the server has changed
+        // since the partial file was written.
+        let Some(if_range) = previous.validators.if_range() else {
+            return Ok(None);
+        };
+        Some(if_range.to_owned())
+    } else if verified {
+        // Validators from one server can't be used with another. Mirrors are meant to serve
+        // identical files, though, and if they don't the checksums will catch it.
+        None
+    } else {
+        return Ok(None);
+    };
+
+    let len = match fs_err::tokio::metadata(temp_path).await {
+        Ok(metadata) => metadata.len(),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    let offset = if previous.segments.is_empty() {
+        len
+    } else {
+        // The temporary file for a segmented download is preallocated, so its length says nothing
+        // about progress -- the segments do.
+        let expected_len = previous.segments.last().map_or(0, |segment| segment.end);
+        if len != expected_len {
+            return Ok(None);
         }
+        previous
+            .segments
+            .iter()
+            .map(|segment| segment.downloaded)
+            .sum()
+    };
+    if offset == 0 {
+        return Ok(None);
     }
+
+    Ok(Some(ResumePoint {
+        offset,
+        if_range,
+        segments: previous.segments,
+    }))
 }
 
+/// Downloads `url` to `temp_path`, then renames it to `path` once it's complete and verified.
+///
+/// If more than one segment is requested and the server supports range requests, the download is
+/// segmented. Otherwise, it uses a single connection.
 async fn download_url_to(
-    client: reqwest::Client,
+    ctx: &WorkerContext,
+    url: Url,
+    transfer: &mut Transfer,
+    resume: Option<ResumePoint>,
+    options: &DownloadOptions,
+    progress: &DownloadProgress,
+    messages: &mut mpsc::UnboundedReceiver<WorkerMessage>,
+) -> Result<WorkerStatus> {
+    let plan = match &resume {
+        // Segmented downloads are resumed as segmented downloads, even if --segments has changed
+        // since.
+        Some(resume) if !resume.segments.is_empty() => Some(segmented::Plan::resume(resume)),
+        None if options.segments.get() > 1 => {
+            Deadline::run(
+                transfer.deadline,
+                segmented::probe(ctx, url.clone(), transfer, options),
+            )
+            .await?
+        }
+        _ => None,
+    };
+    match plan {
+        Some(plan) => {
+            segmented::download(ctx, url, transfer, plan, options, progress, messages).await
+        }
+        None => download_single(ctx, url, transfer, resume, options, progress, messages).await,
+    }
+}
+
+/// Downloads `url` to `temp_path` over a single connection, then renames it to `path` once it's
+/// complete and verified.
+async fn download_single(
+    ctx: &WorkerContext,
     url: Url,
-    path: &Utf8Path,
-    cancel_receiver: oneshot::Receiver<()>,
+    transfer: &mut Transfer,
+    resume: Option<ResumePoint>,
+    options: &DownloadOptions,
+    progress: &DownloadProgress,
+    messages: &mut mpsc::UnboundedReceiver<WorkerMessage>,
 ) -> Result<WorkerStatus> {
-    let response = client.get(url.clone()).send().await?;
+    let WorkerContext {
+        client,
+        db_handle,
+        bytes_received,
+        ..
+    } = ctx;
+    let source = transfer.source(&url).clone();
+    let Transfer {
+        ref mirror,
+        ref path,
+        ref temp_path,
+        ref mut deadline,
+    } = *transfer;
+    let headers = ctx.request_headers(&source, options);
+    let timeouts = options.timeouts;
+    let request = send_request(client, &source, &headers, resume.as_ref(), timeouts.idle);
+    let (response, resume_from) = Deadline::run(*deadline, request).await?;
+
+    // Checksums are computed as data is received. If we're resuming, the data already on disk
+    // needs to be hashed first.
+    let mut verifier = Verifier::new(&options.checksums);
+    if resume_from > 0 && !verifier.is_empty() {
+        verifier = verifier.update_from_file(temp_path, resume_from).await?;
+    }
+
+    let mut validators = validators_from(&response);
+    // The size of the whole file, if the server said.
+    let mut total_bytes = response.content_length().map(|len| resume_from + len);
+    db_handle
+        .update_response(url.clone(), validators.clone(), total_bytes)
+        .await?;
+    progress.start(total_bytes, resume_from);
     let mut stream = response.bytes_stream();
 
     // This is the file handle to which data will be written.
-    let mut f = fs_err::tokio::File::create(path).await?;
-
-    // See https://tokio.rs/tokio/tutorial/select for why pinning is required.
-    let mut cancel_receiver = std::pin::pin!(cancel_receiver);
+    let mut f = if resume_from > 0 {
+        fs_err::tokio::OpenOptions::new()
+            .append(true)
+            .open(temp_path)
+            .await?
+    } else {
+        fs_err::tokio::File::create(temp_path).await?
+    };
 
     // This interval is going to tick every second, and let us print the current status of the
     // download. The first tick happens immediately, so consume it.
-    let start = Instant::now();
     let mut interval = tokio::time::interval(Duration::from_secs(1));
     interval.tick().await;
 
-    // Tracks the number of bytes downloaded.
-    let mut bytes_downloaded = 0;
+    // This stopwatch measures the time spent downloading. It's stopped while the download is
+    // paused, so that the elapsed time (and hence the rate) stays accurate across a SIGTSTP.
+    let mut stopwatch = TokioSw::new_started();
+    // When the download was paused, if it's paused.
+    let mut paused_at = None;
 
-    // Here, we loop over a tokio::select! with three branches:
+    // These timers implement the idle timeout and the overall deadline. The idle timer is reset
+    // every time data is received. Both are reset when the download is resumed after a pause, since
+    // time spent paused doesn't count against either of them.
+    let idle_timer = tokio::time::sleep(timeouts.idle);
+    let mut idle_timer = std::pin::pin!(idle_timer);
+    let deadline_timer = Deadline::timer(*deadline);
+    let mut deadline_timer = std::pin::pin!(deadline_timer);
+
+    // This timer implements rate limiting. After each chunk, reading stops until the timer fires,
+    // for as long as the rate limiters ask for. Time spent throttled doesn't count as idle.
+    let throttle = ctx.throttle(options);
+    let throttle_timer = tokio::time::sleep(Duration::ZERO);
+    let mut throttle_timer = std::pin::pin!(throttle_timer);
+    let mut throttled = false;
+
+    // Set when the download is resumed after a pause. If the connection errors out after that, it
+    // was likely dropped by the server while we were stopped, so reconnect rather than failing.
+    let mut reconnect_on_error = false;
+
+    // Tracks the number of bytes downloaded, including any that were already on disk.
+    let mut bytes_downloaded = resume_from;
+
+    // Here, we loop over a tokio::select! with six branches:
     // 1. A chunk of bytes is received.
-    // 2. A cancellation signal is received.
+    // 2. The throttle timer fires.
     // 3. The interval above.
+    // 4. The idle timer fires.
+    // 5. The deadline timer fires.
+    // 6. A message (cancel, pause or resume) is received.
     //
-    // TODO/exercise (medium): implement a timeout using an additional, fourth branch on the
-    // select!. (reqwest also lets you set timeouts via its client/request APIs -- for this
-    // exercise, don't use that functionality.)
-    //
-    // Hint: Look at the tokio::time crate.
+    // All but the throttle timer and the last are disabled while the download is paused.
     loop {
+        let paused = stopwatch.is_stopped();
         tokio::select! {
-            res = stream.next() => {
+            res = stream.next(), if !paused && !throttled => {
                 match res {
                     Some(Ok(mut bytes)) => {
-                        bytes_downloaded += bytes.len();
+                        bytes_downloaded += bytes.len() as u64;
+                        bytes_received.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                        idle_timer.as_mut().reset(Instant::now() + timeouts.idle);
+                        progress.set_position(bytes_downloaded);
+                        let delay = throttle.reserve(bytes.len() as u64);
+                        if !delay.is_zero() {
+                            throttle_timer.as_mut().reset(Instant::now() + delay);
+                            throttled = true;
+                        }
+                        verifier.update(&bytes);
                         // Write the chunk to the file.
                         f.write_all_buf(&mut bytes).await?;
                     }
+                    Some(Err(error)) if reconnect_on_error => {
+                        tracing::info!(
+                            url = %url,
+                            error = %error,
+                            "Connection lost while paused, reconnecting",
+                        );
+                        reconnect_on_error = false;
+
+                        let resume = validators.if_range().map(|if_range| ResumePoint {
+                            offset: bytes_downloaded,
+                            if_range: Some(if_range.to_owned()),
+                            segments: Vec::new(),
+                        });
+                        let request =
+                            send_request(client, &source, &headers, resume.as_ref(), timeouts.idle);
+                        let (response, offset) = Deadline::run(*deadline, request).await?;
+                        if offset != bytes_downloaded {
+                            // The server sent the whole file, so start writing from scratch.
+                            f.flush().await?;
+                            f.set_len(0).await?;
+                            f.seek(SeekFrom::Start(0)).await?;
+                            bytes_downloaded = 0;
+                            verifier.reset();
+                        }
+                        validators = validators_from(&response);
+                        total_bytes = response.content_length().map(|len| bytes_downloaded + len);
+                        db_handle
+                            .update_response(url.clone(), validators.clone(), total_bytes)
+                            .await?;
+                        progress.start(total_bytes, bytes_downloaded);
+                        stream = response.bytes_stream();
+                    }
                     Some(Err(error)) => {
                         // The stream errored.
                         return Err(error.into());
                     }
                     None => {
-                        // Download completed successfully.
-                        return Ok(WorkerStatus::Completed);
+                        // Download completed successfully. Make sure the data actually hit the
+                        // disk before recording it as complete.
+                        f.sync_all().await?;
+                        db_handle.update_progress(url, bytes_downloaded).await?;
+                        verifier.verify().map_err(DownloadError::from)?;
+
+                        // Only move the file into place once it's known to be good, so that a
+                        // file at `path` is always complete.
+                        std::mem::drop(f);
+                        fs_err::tokio::rename(temp_path, path).await?;
+                        return Ok(WorkerStatus::Completed {
+                            mirror: mirror.clone(),
+                        });
                     }
                 }
             }
-            _ = interval.tick() => {
-                // Print the current status of the download.
-                tracing::info!(url = %url, "{:.2?} elapsed, {bytes_downloaded} bytes downloaded", start.elapsed());
+            () = &mut throttle_timer, if throttled => {
+                throttled = false;
+                idle_timer.as_mut().reset(Instant::now() + timeouts.idle);
             }
-            Ok(_) = &mut cancel_receiver => {
-                // The cancellation signal was received -- flush and close the file.
+            _ = interval.tick(), if !paused => {
+                // Print the current status of the download, unless it's being shown in a progress
+                // bar.
+                if !ctx.progress.is_visible() {
+                    tracing::info!(url = %url, "{:.2?} elapsed, {bytes_downloaded} bytes downloaded", stopwatch.elapsed());
+                }
+                ctx.events.emit(Event::Progress {
+                    url: &url,
+                    bytes_downloaded,
+                    total_bytes,
+                });
+                db_handle.update_progress(url.clone(), bytes_downloaded).await?;
+            }
+            () = &mut idle_timer, if !paused && !throttled => {
+                f.shutdown().await?;
+                db_handle.update_progress(url, bytes_downloaded).await?;
+                return Err(DownloadError::IdleTimeout(timeouts.idle).into());
+            }
+            () = &mut deadline_timer, if !paused && deadline.is_some() => {
                 f.shutdown().await?;
-                return Ok(WorkerStatus::Cancelled);
+                db_handle.update_progress(url, bytes_downloaded).await?;
+                let deadline = deadline.expect("branch is only enabled if there's a deadline");
+                return Err(deadline.exceeded().into());
+            }
+            Some(message) = messages.recv() => {
+                match message {
+                    WorkerMessage::Cancel(kind) => {
+                        // The cancellation signal was received -- flush and close the file. The
+                        // partial file is kept around so a future run can resume it.
+                        f.shutdown().await?;
+                        db_handle.update_progress(url, bytes_downloaded).await?;
+                        return Ok(WorkerStatus::Cancelled(kind));
+                    }
+                    WorkerMessage::Pause(ack) => {
+                        if !paused {
+                            stopwatch.toggle();
+                            paused_at = Some(Instant::now());
+                            // Save our state in case the process is killed while it's stopped.
+                            // pause_workers flushes the database once every worker has acked.
+                            f.flush().await?;
+                            db_handle.update_progress(url.clone(), bytes_downloaded).await?;
+                            db_handle.update_state(url.clone(), DownloadState::Paused).await?;
+                        }
+                        _ = ack.send(());
+                    }
+                    WorkerMessage::Resume => {
+                        if paused {
+                            stopwatch.toggle();
+                            // Don't count the time spent paused towards the next status update, or
+                            // towards either timeout.
+                            interval.reset();
+                            let now = Instant::now();
+                            idle_timer.as_mut().reset(now + timeouts.idle);
+                            if let (Some(deadline), Some(paused_at)) = (deadline.as_mut(), paused_at.take()) {
+                                deadline.at += now - paused_at;
+                                deadline_timer.as_mut().reset(deadline.at);
+                            }
+                            reconnect_on_error = true;
+                            db_handle.update_state(url.clone(), DownloadState::Downloading).await?;
+                        }
+                    }
+                }
             }
         }
     }
 }
 
+/// Sends a request for `url`, resuming from `resume` if possible.
+///
+/// Returns the response, along with the offset its body starts at. If the server doesn't honor
+/// the range request, the offset is 0.
+///
+/// Fails with [`DownloadError::IdleTimeout`] if the server doesn't send headers within
+/// `idle_timeout`.
+async fn send_request(
+    client: &reqwest::Client,
+    url: &Url,
+    headers: &HeaderMap,
+    resume: Option<&ResumePoint>,
+    idle_timeout: Duration,
+) -> Result<(reqwest::Response, u64)> {
+    tracing::debug!(url = %url, headers = %Redacted(headers), "Sending request");
+    let send = |request: reqwest::RequestBuilder| async move {
+        tokio::time::timeout(idle_timeout, request.send())
+            .await
+            .map_err(|_| DownloadError::IdleTimeout(idle_timeout))
+    };
+
+    let mut request = client.get(url.clone()).headers(headers.clone());
+    if let Some(resume) = resume {
+        request = request.header(header::RANGE, format!("bytes={}-", resume.offset));
+        if let Some(if_range) = &resume.if_range {
+            request = request.header(header::IF_RANGE, if_range);
+        }
+    }
+    let mut response = send(request).await??;
+
+    if resume.is_some() && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // The partial file is probably at least as large as the file on the server. Start over.
+        tracing::warn!(url = %url, "Server rejected range request, restarting download");
+        response = send(client.get(url.clone()).headers(headers.clone())).await??;
+    }
+
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        return Err(DownloadError::HttpStatus {
+            status,
+            retry_after: retry_after_from(&response),
+        }
+        .into());
+    }
+
+    // Figure out whether the server honored the range request. If the file changed (the If-Range
+    // validator didn't match) or the server doesn't support ranges, it sends the whole file with a
+    // 200 instead.
+    let offset = match resume {
+        Some(resume) if response.status() == StatusCode::PARTIAL_CONTENT => {
+            check_content_range(&response, resume.offset)?;
+            tracing::info!(url = %url, offset = resume.offset, "Resuming download");
+            resume.offset
+        }
+        Some(_) => {
+            tracing::info!(url = %url, "Server sent the full file, restarting download");
+            0
+        }
+        None => 0,
+    };
+
+    Ok((response, offset))
+}
+
+/// Checks that a 206 response starts where the partial file ends.
+fn check_content_range(response: &reqwest::Response, offset: u64) -> Result<()> {
+    let content_range = response
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| eyre::eyre!("206 response is missing a valid Content-Range header"))?;
+    // The header looks like "bytes 1000-1999/2000".
+    let start = content_range
+        .strip_prefix("bytes ")
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(start, _)| start.parse::<u64>().ok());
+    if start != Some(offset) {
+        eyre::bail!("requested range starting at {offset}, but server sent `{content_range}`");
+    }
+    Ok(())
+}
+
+/// Parses the `Retry-After` header in a response, which is either a number of seconds or a date.
+fn retry_after_from(response: &reqwest::Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    // A date in the past means the server is ready now.
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Extracts the validators from a response, for use in resuming later.
+fn validators_from(response: &reqwest::Response) -> Validators {
+    let get = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_owned())
+    };
+    Validators {
+        etag: get(header::ETAG),
+        last_modified: get(header::LAST_MODIFIED),
+    }
+}
+
+/// Errors specific to downloads, as opposed to general I/O or HTTP errors.
+#[derive(Debug, Error)]
+enum DownloadError {
+    #[error("no data received for {}", humantime::format_duration(*.0))]
+    IdleTimeout(Duration),
+    #[error("download didn't finish within {}", humantime::format_duration(*.0))]
+    DeadlineExceeded(Duration),
+    #[error("server returned {status}")]
+    HttpStatus {
+        status: StatusCode,
+        /// The value of the `Retry-After` header, if any.
+        retry_after: Option<Duration>,
+    },
+    #[error(transparent)]
+    ChecksumMismatch(#[from] ChecksumMismatch),
+    #[error("server ignored a range request for a segment, the file may have changed")]
+    RangeIgnored,
+    #[error("server closed the connection with {0} bytes left in segment")]
+    SegmentTruncated(u64),
+}
+
 #[derive(Debug)]
 struct WorkerOutput {
     url: Url,
@@ -328,26 +1878,177 @@ struct WorkerOutput {
     // Can add other fields here, e.g. time taken, etc.
 }
 
+impl WorkerOutput {
+    /// Logs the outcome of the download, emits an event for it and adds it to `summary`.
+    fn report(self, events: &Events, summary: &mut RunSummary) {
+        let Self { url, path, result } = self;
+        match result {
+            Ok(WorkerStatus::Completed { mirror }) => {
+                tracing::info!(
+                    url = %url,
+                    path = %path,
+                    mirror = mirror.as_ref().map(tracing::field::display),
+                    "Download completed",
+                );
+                events.emit(Event::Completed {
+                    url: &url,
+                    path: &path,
+                    mirror: mirror.as_ref(),
+                });
+                summary.completed += 1;
+                if let Some(mirror) = mirror {
+                    summary.mirrors.push((url, mirror));
+                }
+            }
+            Ok(WorkerStatus::Skipped) => {
+                tracing::info!(url = %url, path = %path, "Download skipped, already completed");
+                events.emit(Event::Skipped {
+                    url: &url,
+                    path: &path,
+                });
+                summary.skipped += 1;
+            }
+            Ok(WorkerStatus::Cancelled(kind)) => {
+                tracing::warn!(url = %url, path = %path, reason = ?kind, "Download cancelled");
+                events.emit(Event::Cancelled {
+                    url: &url,
+                    path: &path,
+                    signal: kind.signal_name(),
+                });
+                summary.cancelled += 1;
+            }
+            Err(error) => {
+                tracing::error!(error = %error, url = %url, path = %path, "Download failed");
+                events.emit(Event::Failed {
+                    url: &url,
+                    path: &path,
+                    error: &format!("{error:#}"),
+                });
+                summary.failed.push(url);
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 enum WorkerStatus {
-    Completed,
-    Cancelled,
+    Completed {
+        /// The mirror the file was downloaded from, if it wasn't the download's own URL.
+        mirror: Option<Url>,
+    },
+    /// The file was already downloaded by a previous run.
+    Skipped,
+    Cancelled(CancelKind),
 }
 
+/// Messages broadcast from the main loop to workers.
 #[derive(Debug, Clone)]
-struct CancelMessage {
-    #[allow(dead_code)]
-    kind: CancelKind,
+enum WorkerMessage {
+    /// Stop the download, flushing and closing the file.
+    Cancel(CancelKind),
+    /// Pause the download. Once the worker has paused and saved its state, it sends a message over
+    /// the channel.
+    Pause(mpsc::UnboundedSender<()>),
+    /// Resume a paused download.
+    Resume,
+}
+
+/// The reason a download was cancelled. This is recorded in the database.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum CancelKind {
+    /// A SIGINT (Ctrl-C) was received.
+    Interrupt,
+    /// A SIGTERM was received, e.g. from a container orchestrator or service manager.
+    Terminate,
+    /// A SIGHUP was received, e.g. because the controlling terminal was closed.
+    Hangup,
+    /// A client of the daemon asked for the download to be cancelled, or for the daemon to shut
+    /// down.
+    Request,
 }
 
-impl CancelMessage {
-    fn new(kind: CancelKind) -> Self {
-        Self { kind }
+impl CancelKind {
+    /// Returns the name of the signal corresponding to this kind, or `None` if it wasn't caused by
+    /// a signal.
+    pub(crate) fn signal_name(self) -> Option<&'static str> {
+        match self {
+            Self::Interrupt => Some("SIGINT"),
+            Self::Terminate => Some("SIGTERM"),
+            Self::Hangup => Some("SIGHUP"),
+            Self::Request => None,
+        }
+    }
+
+    /// Returns the number of the signal corresponding to this kind, or `None` if it wasn't caused
+    /// by a signal.
+    pub(crate) fn signal_number(self) -> Option<u8> {
+        let signo = match self {
+            Self::Interrupt => libc::SIGINT,
+            Self::Terminate => libc::SIGTERM,
+            Self::Hangup => libc::SIGHUP,
+            Self::Request => return None,
+        };
+        Some(signo as u8)
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-enum CancelKind {
-    /// A SIGINT (Ctrl-C) was received.
-    Interrupt,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deadline_in(total: Duration) -> Option<Deadline> {
+        Deadline::start(Timeouts {
+            idle: Duration::from_secs(60),
+            total: Some(total),
+        })
+    }
+
+    #[test]
+    fn retry_delay_backoff() {
+        for attempt in 1..=3 {
+            let full = RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+            let delay = retry_delay(attempt, None);
+            assert!(
+                delay >= full / 2 && delay <= full,
+                "attempt {attempt}: {delay:?}"
+            );
+        }
+        assert!(retry_delay(100, None) <= RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn retry_delay_retry_after() {
+        let retry_after = Duration::from_secs(5);
+        assert_eq!(retry_delay(1, Some(retry_after)), retry_after);
+        assert_eq!(retry_delay(3, Some(retry_after)), retry_after);
+        // A day, e.g. from a far-future HTTP date, is capped.
+        let day = Duration::from_secs(86400);
+        assert_eq!(retry_delay(1, Some(day)), RETRY_MAX_DELAY);
+    }
+
+    #[tokio::test]
+    async fn wait_to_retry_stops_at_deadline() {
+        // The backoff is much longer than the time left, so the wait ends at the deadline and
+        // there's no further attempt.
+        let start = Instant::now();
+        let error =
+            Deadline::wait_to_retry(deadline_in(Duration::from_millis(50)), RETRY_MAX_DELAY)
+                .await
+                .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(matches!(
+            error.downcast_ref::<DownloadError>(),
+            Some(DownloadError::DeadlineExceeded(_))
+        ));
+
+        // With time left, or no deadline at all, the wait lasts for the whole delay.
+        let delay = Duration::from_millis(20);
+        let start = Instant::now();
+        Deadline::wait_to_retry(deadline_in(Duration::from_secs(60)), delay)
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= delay);
+        Deadline::wait_to_retry(None, delay).await.unwrap();
+    }
 }
@@ -0,0 +1,219 @@
+//! The `clean` command, which removes files from the output directory that aren't complete
+//! downloads.
+//!
+//! Three kinds of files are removed:
+//!
+//! * Partial files for downloads recorded as interrupted, failed or timed out. These could be
+//!   resumed by a later run, so cleaning them means starting over.
+//! * Temporary files that no download in the database refers to, e.g. because the database was
+//!   deleted.
+//! * Files not referenced by the manifest, e.g. because an entry was removed from it.
+//!
+//! Temporary files for downloads that might still be in progress are kept, as are the database and
+//! the quarantine directory. The output directory is locked while cleaning, so this fails if a
+//! `run` or a daemon is using it.
+
+use super::{PART_EXTENSION, QUARANTINE_DIR};
+use crate::{
+    db::{self, DownloadState, DB_FILE_NAME, LOCK_FILE_NAME},
+    manifest::{FallbackOptions, Manifest, ManifestFormat, STDIN_PATH},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Args;
+use eyre::{bail, Result};
+use indicatif::BinaryBytes;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::IsTerminal,
+    process::ExitCode,
+};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+#[derive(Debug, Args)]
+pub struct CleanArgs {
+    /// The download manifest, or `-` to read it from standard input
+    #[clap(value_name = "PATH")]
+    manifest: Utf8PathBuf,
+
+    /// The format of the manifest [default: detected from the extension, or TOML]
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    manifest_format: Option<ManifestFormat>,
+
+    /// The output directory to clean
+    #[clap(long, short = 'd', value_name = "DIR", default_value = "out")]
+    out_dir: Utf8PathBuf,
+
+    /// List the files that would be deleted, without deleting them
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Delete files without asking for confirmation
+    #[clap(long, short = 'y')]
+    yes: bool,
+}
+
+/// Why a file is being removed.
+#[derive(Clone, Copy, Debug)]
+enum Reason {
+    /// The partial file of a download that was interrupted or failed.
+    Partial(DownloadState),
+    /// A temporary file that no download refers to.
+    Orphaned,
+    /// A file that the manifest doesn't refer to.
+    Unreferenced,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Partial(state) => write!(f, "partial ({state})"),
+            Self::Orphaned => f.write_str("orphaned"),
+            Self::Unreferenced => f.write_str("unreferenced"),
+        }
+    }
+}
+
+impl CleanArgs {
+    pub(super) async fn exec(self) -> Result<ExitCode> {
+        if !self.dry_run
+            && !self.yes
+            && (self.manifest == STDIN_PATH || !std::io::stdin().is_terminal())
+        {
+            bail!(
+                "can't ask for confirmation because standard input isn't a terminal \
+                 (pass --yes to delete files, or --dry-run to list them)"
+            );
+        }
+
+        let manifest = Manifest::load_without_headers(
+            &self.manifest,
+            self.manifest_format,
+            &FallbackOptions::placeholder(),
+        )
+        .await?;
+        let out_dir = match self.out_dir.canonicalize_utf8() {
+            Ok(out_dir) => out_dir,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                println!(
+                    "Output directory `{}` doesn't exist, nothing to clean",
+                    self.out_dir
+                );
+                return Ok(ExitCode::SUCCESS);
+            }
+            Err(error) => return Err(error.into()),
+        };
+        // Hold the lock until everything has been deleted, so that no other process starts a
+        // download whose partial file is about to be deleted.
+        let _lock = db::lock_out_dir(&out_dir)?;
+        let records = db::read_records(&out_dir).await?;
+
+        let referenced: HashSet<_> = manifest
+            .downloads
+            .iter()
+            .map(|entry| entry.out_path(&out_dir))
+            .collect();
+        // The states of downloads that have a temporary file, keyed by its path.
+        let temp_files: HashMap<_, _> = records
+            .values()
+            .filter_map(|record| Some((record.temp_path.as_ref()?, record.state)))
+            .collect();
+
+        let mut to_remove = Vec::new();
+        for (path, len) in list_files(&out_dir).await? {
+            if referenced.contains(&path) {
+                continue;
+            }
+            let reason = if path.extension() == Some(PART_EXTENSION) {
+                match temp_files.get(&path) {
+                    Some(
+                        state @ (DownloadState::Interrupted
+                        | DownloadState::Failed
+                        | DownloadState::TimedOut),
+                    ) => Reason::Partial(*state),
+                    // A process exited while the download was in progress. The next run will
+                    // resume it.
+                    Some(_) => continue,
+                    None => Reason::Orphaned,
+                }
+            } else {
+                Reason::Unreferenced
+            };
+            to_remove.push((path, len, reason));
+        }
+
+        if to_remove.is_empty() {
+            println!("Nothing to clean in `{}`", self.out_dir);
+            return Ok(ExitCode::SUCCESS);
+        }
+        let total: u64 = to_remove.iter().map(|(_, len, _)| len).sum();
+        for (path, len, reason) in &to_remove {
+            let path = path.strip_prefix(&out_dir).unwrap_or(path);
+            println!(
+                "{:<21}  {:>11}  {path}",
+                reason.to_string(),
+                BinaryBytes(*len).to_string()
+            );
+        }
+        let summary = format!("{} files ({})", to_remove.len(), BinaryBytes(total));
+
+        if self.dry_run {
+            println!("Would delete {summary}");
+            return Ok(ExitCode::SUCCESS);
+        }
+        if !self.yes && !confirm(&format!("Delete {summary}?")).await? {
+            println!("Not deleting anything");
+            return Ok(ExitCode::SUCCESS);
+        }
+        for (path, _, _) in &to_remove {
+            fs_err::tokio::remove_file(path).await?;
+        }
+        println!("Deleted {summary}");
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// Returns every file within `out_dir` along with its size, other than the database and anything in
+/// the quarantine directory.
+async fn list_files(out_dir: &Utf8Path) -> Result<Vec<(Utf8PathBuf, u64)>> {
+    let db_path = out_dir.join(DB_FILE_NAME);
+    let skip = [
+        db_path.clone(),
+        // The database is written to this file before being renamed over the real one.
+        db_path.with_extension("json.tmp"),
+        out_dir.join(LOCK_FILE_NAME),
+        out_dir.join(QUARANTINE_DIR),
+    ];
+
+    let mut files = Vec::new();
+    let mut dirs = vec![out_dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs_err::tokio::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = Utf8PathBuf::try_from(entry.path())?;
+            if skip.contains(&path) {
+                continue;
+            }
+            // Symlinks aren't followed: a symlink to a directory is treated as a file.
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else {
+                let len = entry.metadata().await?.len();
+                files.push((path, len));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Asks the user a yes/no question on the terminal, returning true if they answered yes.
+async fn confirm(question: &str) -> Result<bool> {
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    BufReader::new(tokio::io::stdin())
+        .read_line(&mut answer)
+        .await?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
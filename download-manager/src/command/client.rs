@@ -0,0 +1,489 @@
+//! Commands that talk to a running daemon: `add`, `ls`, `pause`, `resume`, `cancel` and `watch`.
+//!
+//! Each command connects to the daemon's socket, sends a request and prints the response. See the
+//! `control` module for the protocol.
+
+use super::status::StatusFormat;
+use crate::{
+    control::{check_peer, default_socket_path, JobInfo, Request, Response},
+    db::DownloadState,
+    events::MessageFormat,
+    manifest::{Manifest, ManifestFormat},
+    progress::{DownloadProgress, Progress},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Args;
+use eyre::{bail, eyre, Result, WrapErr};
+use indicatif::BinaryBytes;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    process::ExitCode,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixStream,
+    },
+};
+use url::Url;
+
+/// The option for finding the daemon, shared by every client command.
+#[derive(Debug, Args)]
+pub struct SocketArgs {
+    /// The path of the daemon's socket [default: $XDG_RUNTIME_DIR/download-manager.sock, or
+    /// /tmp/download-manager-$UID.sock]
+    #[clap(long, value_name = "PATH")]
+    socket: Option<Utf8PathBuf>,
+}
+
+/// A connection to the daemon.
+struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Client {
+    async fn connect(args: &SocketArgs) -> Result<Self> {
+        let path = args.socket.clone().unwrap_or_else(default_socket_path);
+        let stream = match UnixStream::connect(&path).await {
+            Ok(stream) => stream,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                bail!(
+                    "no daemon is running at `{path}` (start one with `download-manager daemon`, \
+                     or pass --socket if it's listening elsewhere)"
+                );
+            }
+            Err(error) => {
+                return Err(error).wrap_err_with(|| format!("failed to connect to `{path}`"));
+            }
+        };
+        // Another user could be listening on the socket, e.g. if it's in /tmp and they created it
+        // first. Don't send them anything.
+        check_peer(&stream).wrap_err_with(|| format!("refusing to talk to `{path}`"))?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        })
+    }
+
+    /// Sends `request` and returns the response. An error response is turned into an `Err`.
+    async fn request(&mut self, request: &Request) -> Result<Response> {
+        let mut json = serde_json::to_string(request)?;
+        json.push('\n');
+        self.writer.write_all(json.as_bytes()).await?;
+        let line = self
+            .next_line()
+            .await?
+            .ok_or_else(|| eyre!("the daemon closed the connection without responding"))?;
+        match serde_json::from_str(&line)
+            .wrap_err_with(|| format!("invalid response from the daemon: {line}"))?
+        {
+            Response::Error { message } => Err(eyre!(message)),
+            response => Ok(response),
+        }
+    }
+
+    async fn next_line(&mut self) -> Result<Option<String>> {
+        Ok(self.lines.next_line().await?)
+    }
+}
+
+/// Returns an error for a response that doesn't match the request.
+fn unexpected(response: Response) -> eyre::Report {
+    eyre!("unexpected response from the daemon: {response:?}")
+}
+
+#[derive(Debug, Args)]
+pub struct AddArgs {
+    /// A URL to download, or a manifest of downloads (`-` to read it from standard input)
+    #[clap(value_name = "URL|MANIFEST")]
+    target: String,
+
+    /// The format of the manifest [default: detected from the extension, or TOML]
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    manifest_format: Option<ManifestFormat>,
+
+    /// The name to save a URL as, within the daemon's output directory [default: the last segment
+    /// of the URL's path]
+    #[clap(long, value_name = "NAME")]
+    file_name: Option<String>,
+
+    #[clap(flatten)]
+    socket: SocketArgs,
+}
+
+impl AddArgs {
+    pub(super) async fn exec(self) -> Result<ExitCode> {
+        // Anything other than an HTTP or HTTPS URL is a path to a manifest.
+        let request = match Url::parse(&self.target) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => Request::EnqueueUrl {
+                url,
+                file_name: self.file_name,
+            },
+            _ => {
+                if self.file_name.is_some() {
+                    bail!("--file-name can only be used when adding a URL");
+                }
+                // The manifest is sent as-is, since the daemon may be running in a different
+                // directory (or as a different user) and unable to read it.
+                let path = Utf8Path::new(&self.target);
+                let format = self
+                    .manifest_format
+                    .unwrap_or_else(|| ManifestFormat::detect(path));
+                Request::EnqueueManifest {
+                    contents: Manifest::read(path).await?,
+                    format,
+                }
+            }
+        };
+
+        let mut client = Client::connect(&self.socket).await?;
+        let (jobs, duplicates) = match client.request(&request).await? {
+            Response::Enqueued { jobs, duplicates } => (jobs, duplicates),
+            response => return Err(unexpected(response)),
+        };
+        for job in &jobs {
+            println!("Added download {}: {} -> {}", job.id, job.url, job.path);
+        }
+        for url in &duplicates {
+            println!("Already downloading: {url}");
+        }
+        if jobs.is_empty() && duplicates.is_empty() {
+            println!("The manifest has no downloads");
+        }
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct LsArgs {
+    /// The output format
+    #[clap(long, value_enum, value_name = "FORMAT", default_value_t)]
+    format: StatusFormat,
+
+    #[clap(flatten)]
+    socket: SocketArgs,
+}
+
+impl LsArgs {
+    pub(super) async fn exec(self) -> Result<ExitCode> {
+        let mut client = Client::connect(&self.socket).await?;
+        let jobs = match client.request(&Request::List).await? {
+            Response::Jobs { jobs } => jobs,
+            response => return Err(unexpected(response)),
+        };
+
+        match self.format {
+            StatusFormat::Table => print_jobs(&jobs),
+            StatusFormat::Json => {
+                serde_json::to_writer_pretty(io::stdout().lock(), &jobs)?;
+                println!();
+            }
+        }
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+fn print_jobs(jobs: &[JobInfo]) {
+    if jobs.is_empty() {
+        println!("No downloads have been added since the daemon started");
+        return;
+    }
+
+    println!(
+        "{:>4}  {:<11}  {:>11}  {:>11}  {:>4}  URL",
+        "ID", "STATE", "DOWNLOADED", "SIZE", "%"
+    );
+    for job in jobs {
+        let state = match job.state {
+            // A download paused before it started is still queued as far as the database knows.
+            Some(DownloadState::Queued) if job.paused => "paused".to_owned(),
+            Some(state) => state.to_string(),
+            // The URL was added again later, and the database only knows about the latest one.
+            None if job.finished => "finished".to_owned(),
+            None => "pending".to_owned(),
+        };
+        let size = job
+            .total_bytes
+            .map_or_else(|| "-".to_owned(), |bytes| BinaryBytes(bytes).to_string());
+        let percent = match job.total_bytes {
+            Some(total) if total > 0 => (job.bytes_downloaded * 100 / total).to_string(),
+            _ => "-".to_owned(),
+        };
+        println!(
+            "{:>4}  {state:<11}  {:>11}  {size:>11}  {percent:>4}  {}",
+            job.id,
+            BinaryBytes(job.bytes_downloaded).to_string(),
+            job.url,
+        );
+        if let Some(error) = &job.last_error {
+            println!("{:>4}  error: {error}", "");
+        }
+    }
+}
+
+/// The arguments for commands that act on a single download.
+#[derive(Debug, Args)]
+pub struct JobArgs {
+    /// The ID of the download, as shown by `add` and `ls`
+    #[clap(value_name = "ID")]
+    id: u64,
+
+    #[clap(flatten)]
+    socket: SocketArgs,
+}
+
+impl JobArgs {
+    pub(super) async fn pause(self) -> Result<ExitCode> {
+        self.send(Request::Pause { id: self.id }, "Paused").await
+    }
+
+    pub(super) async fn resume(self) -> Result<ExitCode> {
+        self.send(Request::Resume { id: self.id }, "Resumed").await
+    }
+
+    pub(super) async fn cancel(self) -> Result<ExitCode> {
+        self.send(Request::Cancel { id: self.id }, "Cancelled")
+            .await
+    }
+
+    async fn send(&self, request: Request, done: &str) -> Result<ExitCode> {
+        let mut client = Client::connect(&self.socket).await?;
+        match client.request(&request).await? {
+            Response::Ok => {
+                println!("{done} download {}", self.id);
+                Ok(ExitCode::SUCCESS)
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct WatchArgs {
+    /// How to show what the daemon is doing
+    ///
+    /// With `json`, the daemon's events are written to standard output as they arrive. See the
+    /// `events` module for the schema.
+    #[clap(long, value_enum, value_name = "FORMAT", default_value_t)]
+    pub(super) message_format: MessageFormat,
+
+    #[clap(flatten)]
+    socket: SocketArgs,
+}
+
+impl WatchArgs {
+    pub(super) async fn exec(self, progress: Progress) -> Result<ExitCode> {
+        // Downloads that are already running don't start again, so get their progress up front.
+        let mut client = Client::connect(&self.socket).await?;
+        let jobs = match client.request(&Request::List).await? {
+            Response::Jobs { jobs } => jobs,
+            response => return Err(unexpected(response)),
+        };
+        match client.request(&Request::Watch).await? {
+            Response::Ok => {}
+            response => return Err(unexpected(response)),
+        }
+
+        let mut display = Display::new(progress, &jobs);
+        while let Some(line) = client.next_line().await? {
+            if self.message_format == MessageFormat::Json {
+                let mut stdout = io::stdout().lock();
+                writeln!(stdout, "{line}")?;
+                stdout.flush()?;
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(event) => display.show(event),
+                Err(error) => tracing::debug!(error = %error, line, "Ignoring unknown event"),
+            }
+        }
+        display.progress.finish();
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// The events that `watch` shows. Only the fields that are shown are deserialized, and other
+/// events are ignored.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum WatchedEvent {
+    Started {
+        url: Url,
+        path: Utf8PathBuf,
+        attempt: u32,
+        resume_from: u64,
+    },
+    Progress {
+        url: Url,
+        bytes_downloaded: u64,
+        total_bytes: Option<u64>,
+    },
+    Retry {
+        url: Url,
+        attempt: u32,
+        error: String,
+        delay_ms: u64,
+    },
+    Completed {
+        url: Url,
+        path: Utf8PathBuf,
+    },
+    Failed {
+        url: Url,
+        path: Utf8PathBuf,
+        error: String,
+    },
+    Cancelled {
+        url: Url,
+        path: Utf8PathBuf,
+    },
+    Skipped {
+        url: Url,
+        path: Utf8PathBuf,
+    },
+    Shutdown {
+        completed: usize,
+        failed: usize,
+        cancelled: usize,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Shows events as log lines, with a progress bar for each active download.
+struct Display {
+    progress: Progress,
+    bars: HashMap<Url, Bar>,
+    /// The number of downloads that finished while watching.
+    finished: usize,
+}
+
+struct Bar {
+    progress: DownloadProgress,
+    total_bytes: Option<u64>,
+}
+
+impl Display {
+    /// Creates the display, with bars for the downloads among `jobs` that are in progress.
+    fn new(progress: Progress, jobs: &[JobInfo]) -> Self {
+        let mut display = Self {
+            progress,
+            bars: HashMap::new(),
+            finished: 0,
+        };
+        for job in jobs {
+            if matches!(
+                job.state,
+                Some(DownloadState::Downloading | DownloadState::Paused)
+            ) {
+                display.update(&job.url, &job.path, job.bytes_downloaded, job.total_bytes);
+            }
+        }
+        display.set_files();
+        display
+    }
+
+    fn show(&mut self, event: WatchedEvent) {
+        match event {
+            WatchedEvent::Started {
+                url,
+                path,
+                attempt,
+                resume_from,
+            } => {
+                tracing::info!(url = %url, path = %path, attempt, resume_from, "Download started");
+                let total_bytes = self.bars.get(&url).and_then(|bar| bar.total_bytes);
+                self.update(&url, &path, resume_from, total_bytes);
+            }
+            WatchedEvent::Progress {
+                url,
+                bytes_downloaded,
+                total_bytes,
+            } => {
+                if !self.progress.is_visible() {
+                    tracing::info!(url = %url, "{bytes_downloaded} bytes downloaded");
+                }
+                if let Some(bar) = self.bars.get_mut(&url) {
+                    if bar.total_bytes != total_bytes {
+                        bar.total_bytes = total_bytes;
+                        bar.progress.start(total_bytes, bytes_downloaded);
+                    } else {
+                        bar.progress.set_position(bytes_downloaded);
+                    }
+                }
+            }
+            WatchedEvent::Retry {
+                url,
+                attempt,
+                error,
+                delay_ms,
+            } => {
+                tracing::warn!(
+                    url = %url,
+                    error,
+                    attempt,
+                    "Download failed, retrying in {}",
+                    humantime::format_duration(Duration::from_millis(delay_ms)),
+                );
+            }
+            WatchedEvent::Completed { url, path } => {
+                tracing::info!(url = %url, path = %path, "Download completed");
+                self.finish(&url);
+            }
+            WatchedEvent::Failed { url, path, error } => {
+                tracing::error!(error, url = %url, path = %path, "Download failed");
+                self.finish(&url);
+            }
+            WatchedEvent::Cancelled { url, path } => {
+                tracing::warn!(url = %url, path = %path, "Download cancelled");
+                self.finish(&url);
+            }
+            WatchedEvent::Skipped { url, path } => {
+                tracing::info!(url = %url, path = %path, "Download skipped, already completed");
+                self.finish(&url);
+            }
+            WatchedEvent::Shutdown {
+                completed,
+                failed,
+                cancelled,
+            } => {
+                tracing::info!(completed, failed, cancelled, "Daemon shut down");
+            }
+            WatchedEvent::Other => {}
+        }
+    }
+
+    /// Shows the progress of a download, adding a bar for it if there isn't one.
+    fn update(&mut self, url: &Url, path: &Utf8Path, bytes_downloaded: u64, total: Option<u64>) {
+        let bar = self.bars.entry(url.clone()).or_insert_with(|| Bar {
+            progress: self.progress.add(path),
+            total_bytes: total,
+        });
+        bar.total_bytes = total;
+        bar.progress.start(total, bytes_downloaded);
+        self.set_files();
+    }
+
+    /// Removes the bar for a download that finished.
+    fn finish(&mut self, url: &Url) {
+        self.bars.remove(url);
+        self.finished += 1;
+        self.set_files();
+    }
+
+    fn set_files(&self) {
+        self.progress
+            .set_files(self.finished, self.finished + self.bars.len());
+    }
+}
@@ -0,0 +1,613 @@
+//! The `daemon` command, which keeps running in the background and downloads whatever clients ask
+//! it to.
+//!
+//! Clients talk to the daemon over a Unix domain socket, using the protocol in the `control`
+//! module. Unlike `run`, the HTTP client, the limits on concurrent downloads and the database stay
+//! the same for every download, for as long as the daemon runs.
+
+use super::{
+    adjust_rate_limit, worker_fn, CancelKind, WorkerArgs, WorkerContext, WorkerMessage,
+    WorkerOutput,
+};
+use crate::{
+    control::{check_peer, default_socket_path, EnqueuedJob, JobInfo, Request, Response},
+    events::{Event, Events, SignalAction},
+    manifest::{FallbackOptions, Manifest, ManifestEntry},
+    progress::Progress,
+    rate::RateLimiter,
+    summary::RunSummary,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Args;
+use eyre::{bail, Result, WrapErr};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::Permissions,
+    io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    process::ExitCode,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{unix::OwnedWriteHalf, UnixListener, UnixStream},
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinSet,
+    time::{Instant, Sleep},
+};
+use url::Url;
+
+#[derive(Debug, Args)]
+pub struct DaemonArgs {
+    /// The path to listen on for requests [default: $XDG_RUNTIME_DIR/download-manager.sock, or
+    /// /tmp/download-manager-$UID.sock]
+    #[clap(long, value_name = "PATH")]
+    socket: Option<Utf8PathBuf>,
+
+    #[clap(flatten)]
+    worker: WorkerArgs,
+}
+
+/// How long to wait for clients to read the last events after shutting down, before closing their
+/// connections.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl DaemonArgs {
+    pub(super) async fn exec(self) -> Result<ExitCode> {
+        let start = Instant::now();
+        let socket_path = self.socket.clone().unwrap_or_else(default_socket_path);
+        let listener = bind(&socket_path).await?;
+
+        // Per-host settings come from the manifest's `[hosts]` table, and there's no manifest
+        // when the daemon starts, so the daemon doesn't support them. --max-per-host and -H still
+        // apply.
+        let events = Events::with_subscribers();
+        let (ctx, db_task_handle) = self
+            .worker
+            .context(&[], Progress::new(false), events)
+            .await?;
+        tracing::info!(socket = %socket_path, out_dir = %ctx.out_dir, "Daemon listening");
+
+        let mut daemon = Daemon {
+            ctx,
+            fallback: self.worker.fallback(),
+            workers: JoinSet::new(),
+            jobs: BTreeMap::new(),
+            running: HashMap::new(),
+            next_id: 1,
+        };
+        let mut summary = RunSummary::default();
+
+        // Each connection is served by its own task, which passes requests on to the main loop
+        // through this channel.
+        let mut connections = JoinSet::new();
+        let (call_sender, mut call_receiver) = mpsc::channel::<Call>(16);
+
+        // SIGINT, SIGTERM and SIGHUP shut down gracefully, and SIGUSR1 and SIGUSR2 change the rate
+        // limit, as they do with `run`. There's no manifest to reload on SIGHUP, and SIGTSTP is
+        // left alone, since clients can pause downloads.
+        let mut ctrl_c_stream = signal(SignalKind::interrupt())?;
+        let mut sigterm_stream = signal(SignalKind::terminate())?;
+        let mut sighup_stream = signal(SignalKind::hangup())?;
+        let mut sigusr1_stream = signal(SignalKind::user_defined1())?;
+        let mut sigusr2_stream = signal(SignalKind::user_defined2())?;
+
+        // As with `run`, this is the kind of signal (or request) that caused a shutdown, and the
+        // timer bounding how long the shutdown can take.
+        let mut shutting_down = None;
+        let shutdown_timer = tokio::time::sleep(Duration::ZERO);
+        let mut shutdown_timer = std::pin::pin!(shutdown_timer);
+
+        let forced_exit = loop {
+            if shutting_down.is_some() && daemon.workers.is_empty() {
+                break false;
+            }
+            tokio::select! {
+                res = listener.accept(), if shutting_down.is_none() => {
+                    match res {
+                        Ok((stream, _)) => {
+                            // The socket is only writable by us, but there's a moment between
+                            // binding it and setting its permissions when anyone could connect.
+                            match check_peer(&stream) {
+                                Ok(()) => {
+                                    connections.spawn(serve_connection(stream, call_sender.clone()));
+                                }
+                                Err(error) => {
+                                    tracing::warn!(error = %error, "Rejected connection");
+                                }
+                            }
+                        }
+                        Err(error) => tracing::warn!(error = %error, "Failed to accept connection"),
+                    }
+                }
+                Some(res) = connections.join_next() => {
+                    match res {
+                        Ok(Ok(())) => {}
+                        // Usually the client went away in the middle of a response.
+                        Ok(Err(error)) => tracing::debug!(error = %error, "Connection failed"),
+                        Err(error) => tracing::error!(error = %error, "Connection task failed"),
+                    }
+                }
+                Some(res) = daemon.workers.join_next() => {
+                    match res {
+                        Ok(output) => {
+                            if let Some(id) = daemon.running.remove(&output.url) {
+                                if let Some(job) = daemon.jobs.get_mut(&id) {
+                                    job.sender = None;
+                                }
+                            }
+                            output.report(&daemon.ctx.events, &mut summary);
+                        }
+                        Err(error) => {
+                            tracing::error!(error = %error, "Download task failed");
+                            summary.panicked += 1;
+                        }
+                    }
+                }
+                Some(call) = call_receiver.recv() => {
+                    let reply = match call.request {
+                        Request::Shutdown => {
+                            if shutting_down.is_none() {
+                                tracing::info!("Shutdown requested, terminating downloads");
+                                daemon.shutdown(
+                                    &mut shutting_down,
+                                    CancelKind::Request,
+                                    shutdown_timer.as_mut(),
+                                    *self.worker.shutdown_timeout,
+                                );
+                            }
+                            Reply::Response(Response::Ok)
+                        }
+                        // Downloads enqueued now wouldn't be cancelled along with the others,
+                        // and every running download is already being cancelled.
+                        Request::EnqueueManifest { .. }
+                        | Request::EnqueueUrl { .. }
+                        | Request::Pause { .. }
+                        | Request::Resume { .. } if shutting_down.is_some() => {
+                            Reply::Response(Response::error("the daemon is shutting down"))
+                        }
+                        request => daemon.handle(request).await,
+                    };
+                    _ = call.reply.send(reply);
+                }
+                Some(_) = ctrl_c_stream.recv() => {
+                    if shutting_down.is_some() {
+                        tracing::warn!("Ctrl-C received again, exiting immediately");
+                        daemon.ctx.events.emit(Event::SignalReceived { signal: "SIGINT", action: SignalAction::Exit });
+                        break true;
+                    }
+                    tracing::info!("Ctrl-C received, terminating downloads (press Ctrl-C again to exit immediately)");
+                    daemon.ctx.events.emit(Event::SignalReceived { signal: "SIGINT", action: SignalAction::Shutdown });
+                    daemon.shutdown(
+                        &mut shutting_down,
+                        CancelKind::Interrupt,
+                        shutdown_timer.as_mut(),
+                        *self.worker.shutdown_timeout,
+                    );
+                }
+                Some(_) = sigterm_stream.recv() => {
+                    tracing::info!("SIGTERM received, terminating downloads");
+                    daemon.ctx.events.emit(Event::SignalReceived { signal: "SIGTERM", action: SignalAction::Shutdown });
+                    daemon.shutdown(
+                        &mut shutting_down,
+                        CancelKind::Terminate,
+                        shutdown_timer.as_mut(),
+                        *self.worker.shutdown_timeout,
+                    );
+                }
+                Some(_) = sighup_stream.recv() => {
+                    // Usually the terminal the daemon was started from was closed.
+                    tracing::info!("SIGHUP received, terminating downloads");
+                    daemon.ctx.events.emit(Event::SignalReceived { signal: "SIGHUP", action: SignalAction::Shutdown });
+                    daemon.shutdown(
+                        &mut shutting_down,
+                        CancelKind::Hangup,
+                        shutdown_timer.as_mut(),
+                        *self.worker.shutdown_timeout,
+                    );
+                }
+                Some(_) = sigusr1_stream.recv() => {
+                    let action = adjust_rate_limit(&daemon.ctx.rate_limit, "SIGUSR1", 0.5);
+                    daemon.ctx.events.emit(Event::SignalReceived { signal: "SIGUSR1", action });
+                }
+                Some(_) = sigusr2_stream.recv() => {
+                    let action = adjust_rate_limit(&daemon.ctx.rate_limit, "SIGUSR2", 2.0);
+                    daemon.ctx.events.emit(Event::SignalReceived { signal: "SIGUSR2", action });
+                }
+                () = &mut shutdown_timer, if shutting_down.is_some() => {
+                    tracing::warn!(
+                        timeout = %self.worker.shutdown_timeout,
+                        "Downloads didn't stop within the shutdown timeout, exiting immediately",
+                    );
+                    break true;
+                }
+            }
+        };
+
+        // Stop taking requests. Clients that connect from now on get an error, rather than
+        // waiting on a daemon that's going away.
+        std::mem::drop(listener);
+        if let Err(error) = fs_err::tokio::remove_file(&socket_path).await {
+            tracing::warn!(error = %error, "Failed to remove socket");
+        }
+        std::mem::drop(call_receiver);
+
+        if forced_exit {
+            daemon.workers.shutdown().await;
+
+            let kind = shutting_down.expect("forced exits only happen after a shutdown signal");
+            for url in daemon.running.keys() {
+                tracing::warn!(url = %url, "Download abandoned");
+                daemon
+                    .ctx
+                    .db_handle
+                    .mark_interrupted(url.clone(), kind)
+                    .await?;
+            }
+            tracing::error!(
+                abandoned = daemon.running.len(),
+                "Exited before all downloads stopped; partial files may be incomplete",
+            );
+            summary.cancelled += daemon.running.len();
+        }
+
+        summary.bytes = daemon.ctx.bytes_received.load(Ordering::Relaxed);
+        summary.elapsed = start.elapsed();
+        summary.report();
+        let exit_code = summary.exit_code(shutting_down, forced_exit);
+        daemon.ctx.events.emit(Event::Shutdown {
+            completed: summary.completed,
+            failed: summary.failed_count(),
+            cancelled: summary.cancelled,
+            skipped: summary.skipped,
+            bytes: summary.bytes,
+            elapsed_ms: summary.elapsed.as_millis() as u64,
+            signal: shutting_down.and_then(CancelKind::signal_name),
+            forced: forced_exit,
+            exit_code,
+        });
+
+        std::mem::drop(daemon);
+        db_task_handle.await.wrap_err("database task panicked")?;
+
+        // With the database task gone, nothing can emit events any more, so clients watching
+        // them see the end of the stream once they've caught up.
+        let close = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(CLOSE_TIMEOUT, close).await.is_err() {
+            connections.shutdown().await;
+        }
+
+        Ok(ExitCode::from(exit_code))
+    }
+}
+
+/// Binds the control socket at `path`.
+///
+/// A socket left behind by a daemon that didn't exit cleanly is replaced, but it's an error if
+/// another daemon is listening on it.
+async fn bind(path: &Utf8Path) -> Result<UnixListener> {
+    match fs_err::tokio::symlink_metadata(path).await {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            bail!("`{path}` already exists and isn't a socket");
+        }
+        Ok(_) => {
+            if UnixStream::connect(path).await.is_ok() {
+                bail!("a daemon is already listening on `{path}`");
+            }
+            tracing::info!(socket = %path, "Removing stale socket");
+            fs_err::tokio::remove_file(path).await?;
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    let listener = UnixListener::bind(path)
+        .wrap_err_with(|| format!("failed to listen on socket `{path}`"))?;
+    // Only the user running the daemon can control it.
+    fs_err::tokio::set_permissions(path, Permissions::from_mode(0o600)).await?;
+    Ok(listener)
+}
+
+/// A request from a client, passed from its connection's task to the main loop.
+#[derive(Debug)]
+struct Call {
+    request: Request,
+    reply: oneshot::Sender<Reply>,
+}
+
+/// The main loop's answer to a request.
+#[derive(Debug)]
+enum Reply {
+    Response(Response),
+    /// The client asked to watch events. After telling it so, the connection's task forwards
+    /// events from this receiver.
+    Watch(broadcast::Receiver<Arc<str>>),
+}
+
+/// Reads requests from a client and writes back responses, until the client disconnects.
+async fn serve_connection(stream: UnixStream, calls: mpsc::Sender<Call>) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(error) => {
+                let response = Response::error(format!("invalid request: {error}"));
+                write_response(&mut writer, &response).await?;
+                continue;
+            }
+        };
+        tracing::debug!(request = ?request, "Received request");
+
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let call = Call {
+            request,
+            reply: reply_sender,
+        };
+        // Either of these failing means the main loop has exited.
+        let reply = match calls.send(call).await {
+            Ok(()) => reply_receiver.await.ok(),
+            Err(_) => None,
+        };
+        match reply {
+            Some(Reply::Response(response)) => write_response(&mut writer, &response).await?,
+            Some(Reply::Watch(events)) => {
+                write_response(&mut writer, &Response::Ok).await?;
+                return forward_events(events, writer).await;
+            }
+            None => {
+                let response = Response::error("the daemon is shutting down");
+                write_response(&mut writer, &response).await?;
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn write_response(writer: &mut OwnedWriteHalf, response: &Response) -> Result<()> {
+    let mut json = serde_json::to_string(response)?;
+    json.push('\n');
+    writer.write_all(json.as_bytes()).await?;
+    Ok(())
+}
+
+/// Writes events to a client watching the daemon, until the daemon shuts down.
+async fn forward_events(
+    mut events: broadcast::Receiver<Arc<str>>,
+    mut writer: OwnedWriteHalf,
+) -> Result<()> {
+    loop {
+        match events.recv().await {
+            Ok(line) => writer.write_all(line.as_bytes()).await?,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                tracing::warn!(
+                    skipped,
+                    "Client watching events fell behind, skipping events"
+                );
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// The daemon's state, apart from its connections.
+#[derive(Debug)]
+struct Daemon {
+    ctx: WorkerContext,
+    fallback: FallbackOptions,
+    workers: JoinSet<WorkerOutput>,
+    /// Every download enqueued since the daemon started, keyed by ID.
+    jobs: BTreeMap<u64, Job>,
+    /// The IDs of downloads whose workers haven't exited yet, keyed by URL.
+    running: HashMap<Url, u64>,
+    next_id: u64,
+}
+
+/// A download enqueued by a client.
+#[derive(Debug)]
+struct Job {
+    url: Url,
+    path: Utf8PathBuf,
+    /// Sends messages to the download's worker, or `None` once the worker has exited.
+    ///
+    /// Unlike with `run`, each download has its own channel, so that it can be paused or cancelled
+    /// by itself.
+    sender: Option<broadcast::Sender<WorkerMessage>>,
+    paused: bool,
+}
+
+impl Daemon {
+    /// Handles any request other than a shutdown.
+    async fn handle(&mut self, request: Request) -> Reply {
+        let response = match request {
+            Request::EnqueueManifest { contents, format } => {
+                match Manifest::from_contents(&contents, format, &self.fallback) {
+                    Ok(manifest) => {
+                        if !manifest.hosts.is_empty() {
+                            tracing::warn!(
+                                "Ignoring `[hosts]` in enqueued manifest: the daemon doesn't \
+                                 support per-host settings",
+                            );
+                        }
+                        self.enqueue(manifest.downloads)
+                    }
+                    Err(error) => Response::error(format!("error parsing manifest: {error}")),
+                }
+            }
+            Request::EnqueueUrl { url, file_name } => {
+                match ManifestEntry::from_url(url, file_name, &self.fallback) {
+                    Ok(entry) => self.enqueue(vec![entry]),
+                    Err(error) => Response::error(error),
+                }
+            }
+            Request::List => self.list().await,
+            Request::Pause { id } => match self.running_job(id) {
+                Ok(job) if job.paused => {
+                    Response::error(format!("download {id} is already paused"))
+                }
+                Ok(job) => {
+                    tracing::info!(id, url = %job.url, "Pausing download");
+                    // Nothing waits for the worker to acknowledge this, unlike with SIGTSTP: the
+                    // worker saves its state in its own time.
+                    let (ack_sender, _) = mpsc::unbounded_channel();
+                    job.send(WorkerMessage::Pause(ack_sender));
+                    job.paused = true;
+                    Response::Ok
+                }
+                Err(response) => response,
+            },
+            Request::Resume { id } => match self.running_job(id) {
+                Ok(job) if !job.paused => Response::error(format!("download {id} isn't paused")),
+                Ok(job) => {
+                    tracing::info!(id, url = %job.url, "Resuming download");
+                    job.send(WorkerMessage::Resume);
+                    job.paused = false;
+                    Response::Ok
+                }
+                Err(response) => response,
+            },
+            Request::Cancel { id } => match self.running_job(id) {
+                Ok(job) => {
+                    tracing::info!(id, url = %job.url, "Cancelling download");
+                    job.send(WorkerMessage::Cancel(CancelKind::Request));
+                    Response::Ok
+                }
+                Err(response) => response,
+            },
+            Request::Watch => {
+                let events = self
+                    .ctx
+                    .events
+                    .subscribe()
+                    .expect("the daemon's events can be subscribed to");
+                return Reply::Watch(events);
+            }
+            Request::Shutdown => unreachable!("shutdown requests are handled by the main loop"),
+        };
+        Reply::Response(response)
+    }
+
+    /// Spawns workers for `entries`, other than those whose URLs are already being downloaded.
+    fn enqueue(&mut self, entries: Vec<ManifestEntry>) -> Response {
+        let mut jobs = Vec::new();
+        let mut duplicates = Vec::new();
+        for entry in entries {
+            if self.running.contains_key(&entry.url) {
+                tracing::debug!(url = %entry.url, "Download already running, skipping");
+                duplicates.push(entry.url);
+                continue;
+            }
+
+            let id = self.next_id;
+            self.next_id += 1;
+            let path = entry.out_path(&self.ctx.out_dir);
+            tracing::info!(id, url = %entry.url, path = %path, "Enqueueing download");
+            jobs.push(EnqueuedJob {
+                id,
+                url: entry.url.clone(),
+                path: path.clone(),
+            });
+
+            let (sender, receiver) = broadcast::channel(16);
+            let rate_limit = Arc::new(RateLimiter::new(entry.limit_rate));
+            self.running.insert(entry.url.clone(), id);
+            self.jobs.insert(
+                id,
+                Job {
+                    url: entry.url.clone(),
+                    path,
+                    sender: Some(sender),
+                    paused: false,
+                },
+            );
+            self.workers
+                .spawn(worker_fn(self.ctx.clone(), entry, rate_limit, receiver));
+        }
+        Response::Enqueued { jobs, duplicates }
+    }
+
+    /// Lists every job, along with its progress as recorded in the database.
+    async fn list(&self) -> Response {
+        let urls = self.jobs.values().map(|job| job.url.clone()).collect();
+        let mut records = match self.ctx.db_handle.list(urls).await {
+            Ok(records) => records,
+            Err(error) => return Response::error(error.to_string()),
+        };
+
+        // A URL can be enqueued again once its previous download has finished, but the database
+        // only knows about the latest download. Earlier jobs for the URL are listed without it.
+        let latest: HashMap<_, _> = self.jobs.iter().map(|(id, job)| (&job.url, *id)).collect();
+        let jobs = self
+            .jobs
+            .iter()
+            .map(|(id, job)| {
+                let record = if latest[&job.url] == *id {
+                    records.remove(&job.url)
+                } else {
+                    None
+                };
+                JobInfo {
+                    id: *id,
+                    url: job.url.clone(),
+                    path: job.path.clone(),
+                    state: record.as_ref().map(|record| record.state),
+                    paused: job.paused,
+                    finished: job.sender.is_none(),
+                    bytes_downloaded: record.as_ref().map_or(0, |record| record.bytes_downloaded),
+                    total_bytes: record.as_ref().and_then(|record| record.total_bytes),
+                    last_error: record.and_then(|record| record.last_error),
+                }
+            })
+            .collect();
+        Response::Jobs { jobs }
+    }
+
+    /// Returns the job with ID `id`, or an error response if there's no such job or it has
+    /// already finished.
+    fn running_job(&mut self, id: u64) -> Result<&mut Job, Response> {
+        match self.jobs.get_mut(&id) {
+            Some(job) if job.sender.is_some() => Ok(job),
+            Some(_) => Err(Response::error(format!(
+                "download {id} has already finished"
+            ))),
+            None => Err(Response::error(format!("no download with ID {id}"))),
+        }
+    }
+
+    /// Cancels every running download.
+    ///
+    /// If this is the first shutdown signal or request, this also records its kind and starts the
+    /// shutdown timer.
+    fn shutdown(
+        &self,
+        shutting_down: &mut Option<CancelKind>,
+        kind: CancelKind,
+        shutdown_timer: std::pin::Pin<&mut Sleep>,
+        shutdown_timeout: Duration,
+    ) {
+        if shutting_down.is_none() {
+            *shutting_down = Some(kind);
+            shutdown_timer.reset(Instant::now() + shutdown_timeout);
+        }
+        for job in self.jobs.values() {
+            job.send(WorkerMessage::Cancel(kind));
+        }
+    }
+}
+
+impl Job {
+    /// Sends `message` to the job's worker, if it's still running.
+    fn send(&self, message: WorkerMessage) {
+        if let Some(sender) = &self.sender {
+            // An error here means the worker is just exiting, and the main loop hasn't heard
+            // about it yet.
+            _ = sender.send(message);
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! Segmented downloads, where a single file is fetched over several connections at once.
+//!
+//! The file is split into contiguous segments, each of which is fetched with its own range request
+//! and written to its own region of a preallocated temporary file. Progress is tracked per segment
+//! in the database, so that a cancelled or failed download resumes each segment where it left off.
+//!
+//! Segments arrive out of order, so checksums can't be computed as data is received. Instead, the
+//! file is hashed once all segments are complete.
+
+use super::{
+    check_content_range, retry_after_from, validators_from, Deadline, DownloadError,
+    DownloadOptions, ResumePoint, Transfer, WorkerContext, WorkerMessage, WorkerStatus,
+};
+use crate::{
+    checksum::Verifier,
+    db::{DbWorkerHandle, DownloadState, Segment},
+    events::Event,
+    progress::DownloadProgress,
+    rate::Throttle,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use eyre::Result;
+use futures::prelude::*;
+use libsw::TokioSw;
+use reqwest::{
+    header::{self, HeaderMap},
+    StatusCode,
+};
+use std::{
+    io::SeekFrom,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::{mpsc, watch},
+    task::JoinSet,
+    time::Instant,
+};
+use url::Url;
+
+/// Segments smaller than this aren't worth opening a separate connection for.
+const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+/// How often the progress bar is updated while downloading segments.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The segments to download, along with the validator that ensures they all come from the same
+/// version of the file.
+#[derive(Debug)]
+pub(super) struct Plan {
+    segments: Vec<Segment>,
+    if_range: Option<String>,
+}
+
+impl Plan {
+    /// Creates a plan to continue a partially completed segmented download.
+    pub(super) fn resume(resume: &ResumePoint) -> Self {
+        Self {
+            segments: resume.segments.clone(),
+            if_range: resume.if_range.clone(),
+        }
+    }
+}
+
+/// Checks whether the server supports range requests for `url`, and if so, splits the file into
+/// up to `options.segments` segments.
+///
+/// Returns `None` if the file should be downloaded over a single connection instead.
+pub(super) async fn probe(
+    ctx: &WorkerContext,
+    url: Url,
+    transfer: &Transfer,
+    options: &DownloadOptions,
+) -> Result<Option<Plan>> {
+    let source = transfer.source(&url);
+    let idle_timeout = options.timeouts.idle;
+    let request = ctx
+        .client
+        .head(source.clone())
+        .headers(ctx.request_headers(source, options));
+    let response = tokio::time::timeout(idle_timeout, request.send())
+        .await
+        .map_err(|_| DownloadError::IdleTimeout(idle_timeout))??;
+
+    // Some servers don't support HEAD requests. If there's a real problem with the URL, the GET
+    // request will run into it too.
+    if !response.status().is_success() {
+        tracing::info!(
+            url = %url,
+            status = %response.status(),
+            "HEAD request failed, using a single connection",
+        );
+        return Ok(None);
+    }
+
+    let headers = response.headers();
+    let accepts_ranges = headers
+        .get(header::ACCEPT_RANGES)
+        .map_or(false, |value| value == "bytes");
+    // Read the header directly, since the body of a HEAD response is always empty.
+    let len = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let Some(len) = len.filter(|_| accepts_ranges) else {
+        tracing::info!(
+            url = %url,
+            "Server doesn't support range requests, using a single connection",
+        );
+        return Ok(None);
+    };
+
+    let count = (len / MIN_SEGMENT_SIZE).min(options.segments.get() as u64);
+    if count < 2 {
+        tracing::debug!(url = %url, len, "File is too small to split, using a single connection");
+        return Ok(None);
+    }
+
+    let validators = validators_from(&response);
+    ctx.db_handle
+        .update_response(url.clone(), validators.clone(), Some(len))
+        .await?;
+
+    let size = len / count;
+    let segments = (0..count)
+        .map(|i| Segment {
+            start: i * size,
+            // The last segment picks up the remainder.
+            end: if i == count - 1 { len } else { (i + 1) * size },
+            downloaded: 0,
+        })
+        .collect();
+    tracing::info!(url = %url, len, segments = count, "Starting segmented download");
+
+    Ok(Some(Plan {
+        segments,
+        if_range: validators.if_range().map(str::to_owned),
+    }))
+}
+
+/// Downloads the segments in `plan` to `temp_path`, then renames it to `path` once it's complete
+/// and verified.
+///
+/// This handles messages from the main loop the same way as a single-connection download. Pausing
+/// stops all the segments, and resuming starts them again from where they left off.
+pub(super) async fn download(
+    ctx: &WorkerContext,
+    url: Url,
+    transfer: &mut Transfer,
+    plan: Plan,
+    options: &DownloadOptions,
+    progress: &DownloadProgress,
+    messages: &mut mpsc::UnboundedReceiver<WorkerMessage>,
+) -> Result<WorkerStatus> {
+    let db_handle = &ctx.db_handle;
+    let source = transfer.source(&url).clone();
+    let Transfer {
+        ref mirror,
+        ref path,
+        ref temp_path,
+        ref mut deadline,
+    } = *transfer;
+    let headers = ctx.request_headers(&source, options);
+    let Plan { segments, if_range } = plan;
+    let len = segments.last().map_or(0, |segment| segment.end);
+    let resume_from: u64 = segments.iter().map(|segment| segment.downloaded).sum();
+    if resume_from > 0 {
+        tracing::info!(url = %url, offset = resume_from, "Resuming segmented download");
+    }
+
+    // Record the segments before touching the file, so that the database never describes a
+    // preallocated file as an ordinary partial download.
+    db_handle
+        .update_segments(url.clone(), segments.clone())
+        .await?;
+
+    // Preallocate the file, so that each segment can be written at its own offset. If the download
+    // is being resumed, the file already has the right length.
+    let f = fs_err::tokio::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(temp_path)
+        .await?;
+    f.set_len(len).await?;
+
+    let mut running = RunningSegments {
+        segments,
+        progress: Vec::new(),
+        tasks: JoinSet::new(),
+        stop: watch::channel(false).0,
+    };
+    running.spawn(
+        ctx,
+        &source,
+        &headers,
+        if_range.as_deref(),
+        temp_path,
+        options,
+    );
+
+    // See download_single for how the interval, stopwatch and deadline timer are used. There's no
+    // idle timer here: each segment enforces the idle timeout on its own connection.
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    interval.tick().await;
+
+    // Segment tasks only record their progress in atomics, so the progress bar is updated from
+    // them on a separate, faster interval.
+    progress.start(Some(len), running.downloaded());
+    let mut progress_interval = tokio::time::interval(PROGRESS_INTERVAL);
+    let mut stopwatch = TokioSw::new_started();
+    let mut paused_at = None;
+    let deadline_timer = Deadline::timer(*deadline);
+    let mut deadline_timer = std::pin::pin!(deadline_timer);
+
+    loop {
+        let paused = stopwatch.is_stopped();
+        tokio::select! {
+            res = running.tasks.join_next(), if !paused => {
+                match res {
+                    Some(Ok(Ok(()))) => {}
+                    Some(Ok(Err(error))) => {
+                        running.stop().await;
+                        if let Some(DownloadError::RangeIgnored) = error.downcast_ref() {
+                            // The file probably changed on the server, so the segments downloaded
+                            // so far are useless. Throw them away so that a retry starts over.
+                            f.set_len(0).await?;
+                            db_handle.update_segments(url, Vec::new()).await?;
+                        } else {
+                            running.save(db_handle, &url, &f).await?;
+                        }
+                        return Err(error);
+                    }
+                    Some(Err(error)) => {
+                        // A segment task panicked.
+                        running.stop().await;
+                        running.save(db_handle, &url, &f).await?;
+                        return Err(error.into());
+                    }
+                    None => {
+                        // All segments are complete. Make sure the data actually hit the disk
+                        // before recording it as complete. (Syncing any handle to the file syncs
+                        // the writes made through all of them.)
+                        f.sync_all().await?;
+                        db_handle.update_segments(url, running.snapshot()).await?;
+
+                        if !options.checksums.is_empty() {
+                            Verifier::new(&options.checksums)
+                                .update_from_file(temp_path, len)
+                                .await?
+                                .verify()
+                                .map_err(DownloadError::from)?;
+                        }
+
+                        // Only move the file into place once it's known to be good, so that a
+                        // file at `path` is always complete.
+                        std::mem::drop(f);
+                        fs_err::tokio::rename(temp_path, path).await?;
+                        return Ok(WorkerStatus::Completed {
+                            mirror: mirror.clone(),
+                        });
+                    }
+                }
+            }
+            _ = interval.tick(), if !paused => {
+                if !ctx.progress.is_visible() {
+                    tracing::info!(
+                        url = %url,
+                        "{:.2?} elapsed, {} bytes downloaded",
+                        stopwatch.elapsed(),
+                        running.downloaded(),
+                    );
+                }
+                ctx.events.emit(Event::Progress {
+                    url: &url,
+                    bytes_downloaded: running.downloaded(),
+                    total_bytes: Some(len),
+                });
+                running.save(db_handle, &url, &f).await?;
+            }
+            _ = progress_interval.tick(), if !paused && ctx.progress.is_visible() => {
+                progress.set_position(running.downloaded());
+            }
+            () = &mut deadline_timer, if !paused && deadline.is_some() => {
+                running.stop().await;
+                running.save(db_handle, &url, &f).await?;
+                let deadline = deadline.expect("branch is only enabled if there's a deadline");
+                return Err(deadline.exceeded().into());
+            }
+            Some(message) = messages.recv() => {
+                match message {
+                    WorkerMessage::Cancel(kind) => {
+                        // Stop the segments, which flushes what they've written so far. The
+                        // partial file is kept around so a future run can resume it.
+                        running.stop().await;
+                        running.save(db_handle, &url, &f).await?;
+                        return Ok(WorkerStatus::Cancelled(kind));
+                    }
+                    WorkerMessage::Pause(ack) => {
+                        if !paused {
+                            stopwatch.toggle();
+                            paused_at = Some(Instant::now());
+                            // Rather than keeping idle connections open, stop the segments and
+                            // save our state in case the process is killed while it's stopped.
+                            // pause_workers flushes the database once every worker has acked.
+                            running.stop().await;
+                            running.save(db_handle, &url, &f).await?;
+                            db_handle.update_state(url.clone(), DownloadState::Paused).await?;
+                        }
+                        _ = ack.send(());
+                    }
+                    WorkerMessage::Resume => {
+                        if paused {
+                            stopwatch.toggle();
+                            interval.reset();
+                            if let (Some(deadline), Some(paused_at)) = (deadline.as_mut(), paused_at.take()) {
+                                deadline.at += paused_at.elapsed();
+                                deadline_timer.as_mut().reset(deadline.at);
+                            }
+                            running.spawn(ctx, &source, &headers, if_range.as_deref(), temp_path, options);
+                            db_handle.update_state(url.clone(), DownloadState::Downloading).await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The tasks downloading each segment.
+#[derive(Debug)]
+struct RunningSegments {
+    /// The segments, as of when the tasks were last spawned.
+    segments: Vec<Segment>,
+    /// The number of bytes downloaded for each segment, updated by the tasks as they go.
+    progress: Vec<Arc<AtomicU64>>,
+    tasks: JoinSet<Result<()>>,
+    /// Tells the tasks to stop.
+    stop: watch::Sender<bool>,
+}
+
+impl RunningSegments {
+    /// Spawns a task for each incomplete segment.
+    fn spawn(
+        &mut self,
+        ctx: &WorkerContext,
+        url: &Url,
+        headers: &HeaderMap,
+        if_range: Option<&str>,
+        temp_path: &Utf8Path,
+        options: &DownloadOptions,
+    ) {
+        self.segments = self.snapshot();
+        self.progress = self
+            .segments
+            .iter()
+            .map(|segment| Arc::new(AtomicU64::new(segment.downloaded)))
+            .collect();
+        let (stop_sender, stop_receiver) = watch::channel(false);
+        self.stop = stop_sender;
+
+        let throttle = ctx.throttle(options);
+        for (segment, progress) in self.segments.iter().zip(&self.progress) {
+            if segment.is_complete() {
+                continue;
+            }
+            let task = SegmentTask {
+                client: ctx.client.clone(),
+                url: url.clone(),
+                headers: headers.clone(),
+                if_range: if_range.map(str::to_owned),
+                temp_path: temp_path.to_owned(),
+                segment: *segment,
+                progress: progress.clone(),
+                bytes_received: ctx.bytes_received.clone(),
+                throttle: throttle.clone(),
+                idle_timeout: options.timeouts.idle,
+                stop: stop_receiver.clone(),
+            };
+            self.tasks.spawn(task.run());
+        }
+    }
+
+    /// Returns the total number of bytes downloaded across all segments.
+    fn downloaded(&self) -> u64 {
+        self.progress
+            .iter()
+            .map(|progress| progress.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Tells all the tasks to stop, and waits for them to do so.
+    async fn stop(&mut self) {
+        _ = self.stop.send(true);
+        while let Some(res) = self.tasks.join_next().await {
+            // The error that caused the download to stop (if any) has already been reported.
+            // Others are likely knock-on effects of the same problem.
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => tracing::debug!(error = %error, "Segment failed while stopping"),
+                Err(error) => tracing::debug!(error = %error, "Segment task failed while stopping"),
+            }
+        }
+    }
+
+    /// Records the progress of each segment in the database, once the data it covers is on disk.
+    ///
+    /// The file is preallocated, so a range recorded as downloaded that never made it to disk (e.g.
+    /// because of a power loss) would read back as zeroes when the download is resumed.
+    async fn save(
+        &self,
+        db_handle: &DbWorkerHandle,
+        url: &Url,
+        f: &fs_err::tokio::File,
+    ) -> Result<()> {
+        // Segment tasks only count data once it's been written to the file, so syncing after
+        // taking the snapshot covers everything in it.
+        let segments = self.snapshot();
+        f.sync_data().await?;
+        db_handle.update_segments(url.clone(), segments).await?;
+        Ok(())
+    }
+
+    /// Returns the current progress of each segment.
+    fn snapshot(&self) -> Vec<Segment> {
+        if self.progress.is_empty() {
+            // No tasks have been spawned yet.
+            return self.segments.clone();
+        }
+        self.segments
+            .iter()
+            .zip(&self.progress)
+            .map(|(segment, progress)| Segment {
+                downloaded: progress.load(Ordering::Relaxed),
+                ..*segment
+            })
+            .collect()
+    }
+}
+
+/// Downloads a single segment into its region of the temporary file.
+#[derive(Debug)]
+struct SegmentTask {
+    client: reqwest::Client,
+    url: Url,
+    headers: HeaderMap,
+    if_range: Option<String>,
+    temp_path: Utf8PathBuf,
+    segment: Segment,
+    /// The number of bytes downloaded for this segment.
+    progress: Arc<AtomicU64>,
+    /// The number of bytes received by all workers.
+    bytes_received: Arc<AtomicU64>,
+    /// The download's rate limiters, shared by all of its segments.
+    throttle: Throttle,
+    idle_timeout: Duration,
+    stop: watch::Receiver<bool>,
+}
+
+impl SegmentTask {
+    async fn run(mut self) -> Result<()> {
+        let position = self.segment.position();
+
+        // Range headers are inclusive at both ends.
+        let mut request = self
+            .client
+            .get(self.url.clone())
+            .headers(self.headers.clone())
+            .header(
+                header::RANGE,
+                format!("bytes={position}-{}", self.segment.end - 1),
+            );
+        if let Some(if_range) = &self.if_range {
+            request = request.header(header::IF_RANGE, if_range);
+        }
+        let send = tokio::time::timeout(self.idle_timeout, request.send());
+        let response = tokio::select! {
+            res = send => res.map_err(|_| DownloadError::IdleTimeout(self.idle_timeout))??,
+            _ = self.stop.changed() => return Ok(()),
+        };
+
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(DownloadError::HttpStatus {
+                status,
+                retry_after: retry_after_from(&response),
+            }
+            .into());
+        }
+        if status != StatusCode::PARTIAL_CONTENT {
+            return Err(DownloadError::RangeIgnored.into());
+        }
+        check_content_range(&response, position)?;
+
+        let mut f = fs_err::tokio::OpenOptions::new()
+            .write(true)
+            .open(&self.temp_path)
+            .await?;
+        f.seek(SeekFrom::Start(position)).await?;
+
+        let mut remaining = self.segment.end - position;
+        let mut stream = response.bytes_stream();
+        while remaining > 0 {
+            let next = tokio::time::timeout(self.idle_timeout, stream.next());
+            let res = tokio::select! {
+                res = next => res.map_err(|_| DownloadError::IdleTimeout(self.idle_timeout))?,
+                _ = self.stop.changed() => break,
+            };
+            let Some(bytes) = res else {
+                return Err(DownloadError::SegmentTruncated(remaining).into());
+            };
+            let mut bytes = bytes?;
+            // Never write past the end of the segment, even if the server sends too much.
+            bytes.truncate(remaining.min(bytes.len() as u64) as usize);
+
+            let len = bytes.len() as u64;
+            f.write_all_buf(&mut bytes).await?;
+            // Tokio writes to files in the background. Wait for the write to finish before
+            // counting it, so that syncing the file covers everything counted so far.
+            f.flush().await?;
+            remaining -= len;
+            self.progress.fetch_add(len, Ordering::Relaxed);
+            self.bytes_received.fetch_add(len, Ordering::Relaxed);
+
+            let delay = self.throttle.reserve(len);
+            if !delay.is_zero() {
+                tokio::select! {
+                    () = tokio::time::sleep(delay) => {}
+                    _ = self.stop.changed() => break,
+                }
+            }
+        }
+        Ok(())
+    }
+}
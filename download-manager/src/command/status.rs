@@ -0,0 +1,171 @@
+//! The `status` command, which reports the state of downloads as recorded in the database.
+//!
+//! This only reads the database file, so it can be run while another process is downloading to the
+//! same directory.
+
+use super::CancelKind;
+use crate::db::{self, DownloadRecord, DownloadState};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Args, ValueEnum};
+use eyre::Result;
+use indicatif::BinaryBytes;
+use serde::Serialize;
+use std::{io, process::ExitCode, time::SystemTime};
+use url::Url;
+
+#[derive(Debug, Args)]
+pub struct StatusArgs {
+    /// The output directory that downloads were made to
+    #[clap(long, short = 'd', value_name = "DIR", default_value = "out")]
+    out_dir: Utf8PathBuf,
+
+    /// Only show downloads in this state (can be passed multiple times, or as a comma-separated
+    /// list)
+    #[clap(long, value_enum, value_name = "STATE", value_delimiter = ',')]
+    state: Vec<DownloadState>,
+
+    /// The output format
+    #[clap(long, value_enum, value_name = "FORMAT", default_value_t)]
+    format: StatusFormat,
+}
+
+/// How to print the status of downloads. This is also used by `ls`.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub(super) enum StatusFormat {
+    /// A table meant for people, with one row per download.
+    #[default]
+    Table,
+    /// A JSON array with one object per download.
+    Json,
+}
+
+/// The status of a single download, as printed by `--format json`.
+#[derive(Debug, Serialize)]
+struct DownloadStatus<'a> {
+    url: &'a Url,
+    state: DownloadState,
+    path: &'a Utf8Path,
+    /// The number of bytes of the file on disk, or null if there's no file.
+    bytes_on_disk: Option<u64>,
+    /// The size of the whole file, or null if it isn't known.
+    total_bytes: Option<u64>,
+    attempt: u32,
+    mirror: Option<&'a Url>,
+    last_error: Option<&'a str>,
+    interrupted_by: Option<CancelKind>,
+    #[serde(with = "humantime_serde")]
+    started_at: SystemTime,
+    #[serde(with = "humantime_serde")]
+    updated_at: SystemTime,
+    #[serde(with = "humantime_serde")]
+    finished_at: Option<SystemTime>,
+}
+
+impl StatusArgs {
+    pub(super) async fn exec(self) -> Result<ExitCode> {
+        let records = db::read_records(&self.out_dir).await?;
+
+        let mut statuses = Vec::new();
+        for (url, record) in &records {
+            if !self.state.is_empty() && !self.state.contains(&record.state) {
+                continue;
+            }
+            statuses.push(DownloadStatus {
+                url,
+                state: record.state,
+                path: &record.path,
+                bytes_on_disk: bytes_on_disk(record).await?,
+                total_bytes: total_bytes(record),
+                attempt: record.attempt,
+                mirror: record.mirror.as_ref(),
+                last_error: record.last_error.as_deref(),
+                interrupted_by: record.interrupted_by,
+                started_at: record.started_at,
+                updated_at: record.updated_at,
+                finished_at: record.finished_at,
+            });
+        }
+
+        match self.format {
+            StatusFormat::Table => print_table(&self.out_dir, &statuses),
+            StatusFormat::Json => {
+                serde_json::to_writer_pretty(io::stdout().lock(), &statuses)?;
+                println!();
+            }
+        }
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+fn print_table(out_dir: &Utf8Path, statuses: &[DownloadStatus<'_>]) {
+    if statuses.is_empty() {
+        println!("No matching downloads recorded in `{out_dir}`");
+        return;
+    }
+
+    println!(
+        "{:<11}  {:>11}  {:>11}  {:<20}  {:<20}  URL",
+        "STATE", "ON DISK", "SIZE", "STARTED", "UPDATED",
+    );
+    for status in statuses {
+        let on_disk = match status.bytes_on_disk {
+            Some(bytes) => BinaryBytes(bytes).to_string(),
+            None if status.state == DownloadState::Completed => "missing".to_owned(),
+            None => "-".to_owned(),
+        };
+        let size = status
+            .total_bytes
+            .map_or_else(|| "-".to_owned(), |bytes| BinaryBytes(bytes).to_string());
+        println!(
+            "{:<11}  {on_disk:>11}  {size:>11}  {:<20}  {:<20}  {}",
+            status.state.to_string(),
+            humantime::format_rfc3339_seconds(status.started_at).to_string(),
+            humantime::format_rfc3339_seconds(status.updated_at).to_string(),
+            status.url,
+        );
+        if let Some(error) = status.last_error {
+            println!("{:<11}  error: {error}", "");
+        }
+    }
+}
+
+/// Returns the number of bytes of a download that are on disk, or `None` if there's no file.
+///
+/// For a completed download this is the size of the file itself, and otherwise it's the amount of
+/// data in the temporary file.
+async fn bytes_on_disk(record: &DownloadRecord) -> Result<Option<u64>> {
+    let path = match (record.state, &record.temp_path) {
+        (DownloadState::Completed, _) => &record.path,
+        (_, Some(temp_path)) => temp_path,
+        // Nothing has been written yet, or a corrupt file was moved to quarantine.
+        (_, None) => return Ok(None),
+    };
+    let len = match fs_err::tokio::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    if record.segments.is_empty() {
+        Ok(Some(len))
+    } else {
+        // The temporary file for a segmented download is preallocated, so its length says nothing
+        // about progress -- the segments do.
+        Ok(Some(
+            record
+                .segments
+                .iter()
+                .map(|segment| segment.downloaded)
+                .sum(),
+        ))
+    }
+}
+
+/// Returns the size of the whole file, if it's known.
+fn total_bytes(record: &DownloadRecord) -> Option<u64> {
+    if record.state == DownloadState::Completed {
+        return Some(record.bytes_downloaded);
+    }
+    record
+        .total_bytes
+        .or_else(|| record.segments.last().map(|segment| segment.end))
+}
@@ -0,0 +1,225 @@
+//! The `verify` command, which checks files that were already downloaded without downloading them
+//! again.
+//!
+//! Each file is checked against the size recorded in the database, and against the checksums in
+//! the manifest along with any others recorded when it was downloaded.
+
+use crate::{
+    checksum::{Checksum, ChecksumMismatch, Verifier},
+    db::{self, DownloadRecord, DownloadState},
+    manifest::{FallbackOptions, Manifest, ManifestEntry, ManifestFormat},
+    summary::SOME_FAILED_EXIT_CODE,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Args;
+use eyre::Result;
+use futures::prelude::*;
+use std::{collections::HashSet, io, num::NonZeroUsize, process::ExitCode};
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// The download manifest, or `-` to read it from standard input
+    #[clap(value_name = "PATH")]
+    manifest: Utf8PathBuf,
+
+    /// The format of the manifest [default: detected from the extension, or TOML]
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    manifest_format: Option<ManifestFormat>,
+
+    /// The output directory that files were downloaded to
+    #[clap(long, short = 'd', value_name = "DIR", default_value = "out")]
+    out_dir: Utf8PathBuf,
+
+    /// The maximum number of files to hash at the same time [default: the number of CPUs]
+    #[clap(long, short = 'j', value_name = "N")]
+    jobs: Option<NonZeroUsize>,
+}
+
+impl VerifyArgs {
+    pub(super) async fn exec(self) -> Result<ExitCode> {
+        let manifest = Manifest::load_without_headers(
+            &self.manifest,
+            self.manifest_format,
+            &FallbackOptions::placeholder(),
+        )
+        .await?;
+        let records = db::read_records(&self.out_dir).await?;
+
+        // As with `run`, only the first entry for each URL counts.
+        let mut seen = HashSet::new();
+        let entries = manifest
+            .downloads
+            .iter()
+            .filter(|entry| seen.insert(&entry.url));
+
+        // Files are hashed on the blocking pool, so running several checks at once hashes them in
+        // parallel. Results are still reported in manifest order.
+        let jobs = self
+            .jobs
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get);
+        let mut results = stream::iter(entries)
+            .map(|entry| {
+                let path = entry.out_path(&self.out_dir);
+                let record = completed_record(entry, records.get(&entry.url));
+                async move {
+                    let status = verify_file(&path, entry, record).await;
+                    (path, status)
+                }
+            })
+            .buffered(jobs);
+
+        let mut counts: Vec<(&str, usize)> = Vec::new();
+        let mut problems = 0;
+        while let Some((path, status)) = results.next().await {
+            let label = status.label();
+            match &status {
+                FileStatus::Ok | FileStatus::Unchecked | FileStatus::Missing => {
+                    println!("{label:<9}  {path}");
+                }
+                FileStatus::Truncated { expected, actual }
+                | FileStatus::Oversized { expected, actual } => {
+                    println!("{label:<9}  {path}: expected {expected} bytes, found {actual}");
+                }
+                FileStatus::Corrupt(mismatch) => println!("{label:<9}  {path}: {mismatch}"),
+                FileStatus::Error(error) => println!("{label:<9}  {path}: {error:#}"),
+            }
+            if status.is_problem() {
+                problems += 1;
+            }
+            match counts.iter_mut().find(|(l, _)| *l == label) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((label, 1)),
+            }
+        }
+
+        let total: usize = counts.iter().map(|(_, count)| count).sum();
+        let counts: Vec<_> = counts
+            .iter()
+            .map(|(label, count)| format!("{count} {label}"))
+            .collect();
+        if counts.is_empty() {
+            println!("Checked {total} files");
+        } else {
+            println!("Checked {total} files: {}", counts.join(", "));
+        }
+
+        if problems > 0 {
+            Ok(ExitCode::from(SOME_FAILED_EXIT_CODE))
+        } else {
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+/// The result of checking a single file.
+#[derive(Debug)]
+enum FileStatus {
+    /// The file matches its recorded size and all of its checksums.
+    Ok,
+    /// The file exists, but there's no recorded size or checksum to check it against.
+    Unchecked,
+    Missing,
+    /// The file is smaller than when it was downloaded.
+    Truncated {
+        expected: u64,
+        actual: u64,
+    },
+    /// The file is larger than when it was downloaded.
+    Oversized {
+        expected: u64,
+        actual: u64,
+    },
+    /// The file doesn't match one of its checksums.
+    Corrupt(ChecksumMismatch),
+    /// The file couldn't be read.
+    Error(eyre::Report),
+}
+
+impl FileStatus {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Unchecked => "unchecked",
+            Self::Missing => "missing",
+            Self::Truncated { .. } => "truncated",
+            Self::Oversized { .. } => "oversized",
+            Self::Corrupt(_) => "corrupt",
+            Self::Error(_) => "error",
+        }
+    }
+
+    fn is_problem(&self) -> bool {
+        !matches!(self, Self::Ok | Self::Unchecked)
+    }
+}
+
+/// Returns the database record for `entry`, if it records a completed download of the same file.
+fn completed_record<'a>(
+    entry: &ManifestEntry,
+    record: Option<&'a DownloadRecord>,
+) -> Option<&'a DownloadRecord> {
+    // Paths in the database are absolute, so compare the part within the output directory. This
+    // way, records still apply if the output directory has been moved or copied elsewhere.
+    record.filter(|record| {
+        record.state == DownloadState::Completed && record.path.ends_with(&entry.path)
+    })
+}
+
+async fn verify_file(
+    path: &Utf8Path,
+    entry: &ManifestEntry,
+    record: Option<&DownloadRecord>,
+) -> FileStatus {
+    let len = match fs_err::tokio::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return FileStatus::Missing,
+        Err(error) => return FileStatus::Error(error.into()),
+    };
+
+    let expected_len = record.map(|record| record.bytes_downloaded);
+    match expected_len {
+        Some(expected) if len < expected => {
+            return FileStatus::Truncated {
+                expected,
+                actual: len,
+            }
+        }
+        Some(expected) if len > expected => {
+            return FileStatus::Oversized {
+                expected,
+                actual: len,
+            }
+        }
+        _ => {}
+    }
+
+    // Checksums in the manifest take precedence over ones recorded for the same algorithm, since
+    // they're what a new download would be checked against.
+    let mut checksums = entry.checksums.clone();
+    for (algorithm, expected) in record.iter().flat_map(|record| &record.checksums) {
+        if !checksums
+            .iter()
+            .any(|checksum| checksum.algorithm == *algorithm)
+        {
+            checksums.push(Checksum {
+                algorithm: *algorithm,
+                expected: expected.clone(),
+            });
+        }
+    }
+    if checksums.is_empty() {
+        return match expected_len {
+            Some(_) => FileStatus::Ok,
+            None => FileStatus::Unchecked,
+        };
+    }
+
+    match Verifier::new(&checksums).update_from_file(path, len).await {
+        Ok(verifier) => match verifier.verify() {
+            Ok(()) => FileStatus::Ok,
+            Err(mismatch) => FileStatus::Corrupt(mismatch),
+        },
+        Err(error) => FileStatus::Error(error),
+    }
+}
@@ -0,0 +1,140 @@
+//! The protocol spoken over the daemon's control socket.
+//!
+//! The daemon listens on a Unix domain socket. Clients send requests and the daemon sends back
+//! responses, each of which is a single line of JSON. A connection can be used for any number of
+//! requests, and each request gets exactly one response, in order.
+//!
+//! After a `watch` request is answered with `ok`, the daemon instead writes every event (see the
+//! `events` module) to the connection as it happens, until the client disconnects or the daemon
+//! shuts down.
+
+use crate::{db::DownloadState, manifest::ManifestFormat};
+use camino::{Utf8Path, Utf8PathBuf};
+use eyre::{bail, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use tokio::net::UnixStream;
+use url::Url;
+
+/// The name of the socket, within `$XDG_RUNTIME_DIR`.
+const SOCKET_NAME: &str = "download-manager.sock";
+
+/// Returns the path of the control socket used if `--socket` isn't passed.
+///
+/// This is `$XDG_RUNTIME_DIR/download-manager.sock` if `XDG_RUNTIME_DIR` is set, since that
+/// directory is private to the user. Otherwise, it's `/tmp/download-manager-$UID.sock`. Since
+/// another user could create that path first, clients check who's listening with [`check_peer`],
+/// and the daemon checks who's connecting.
+pub(crate) fn default_socket_path() -> Utf8PathBuf {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => Utf8Path::new(&dir).join(SOCKET_NAME),
+        _ => {
+            // SAFETY: getuid has no memory safety preconditions, and can't fail.
+            let uid = unsafe { libc::getuid() };
+            format!("/tmp/download-manager-{uid}.sock").into()
+        }
+    }
+}
+
+/// Checks that the process at the other end of `stream` is running as the current user.
+///
+/// This must be called before anything is sent over the connection, since requests can carry
+/// secrets such as header values.
+pub(crate) fn check_peer(stream: &UnixStream) -> Result<()> {
+    let peer = stream
+        .peer_cred()
+        .wrap_err("failed to get the credentials of the other end of the socket")?
+        .uid();
+    // SAFETY: getuid has no memory safety preconditions, and can't fail.
+    let uid = unsafe { libc::getuid() };
+    if peer != uid {
+        bail!(
+            "the other end of the socket is running as uid {peer}, not as the current user ({uid})"
+        );
+    }
+    Ok(())
+}
+
+/// A request sent to the daemon.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub(crate) enum Request {
+    /// Download the entries in a manifest. The manifest's `[hosts]` table is ignored, since the
+    /// daemon doesn't support per-host settings.
+    EnqueueManifest {
+        /// The contents of the manifest (rather than its path, since the daemon may be running in
+        /// a different directory).
+        contents: String,
+        format: ManifestFormat,
+    },
+    /// Download a single URL, with the daemon's default options.
+    EnqueueUrl {
+        url: Url,
+        /// The name of the file to download to [default: the last segment of the URL's path].
+        file_name: Option<String>,
+    },
+    /// List the downloads enqueued since the daemon started.
+    List,
+    /// Pause a download. A download that's still queued doesn't start until it's resumed.
+    Pause { id: u64 },
+    /// Resume a paused download.
+    Resume { id: u64 },
+    /// Cancel a download. Its partial file is kept, so enqueueing it again resumes it.
+    Cancel { id: u64 },
+    /// Stream events from the daemon.
+    Watch,
+    /// Cancel every download and exit.
+    Shutdown,
+}
+
+/// A response from the daemon.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub(crate) enum Response {
+    /// The request succeeded, and has nothing else to report.
+    Ok,
+    /// Downloads were enqueued.
+    Enqueued {
+        jobs: Vec<EnqueuedJob>,
+        /// URLs that weren't enqueued, because they're already being downloaded.
+        duplicates: Vec<Url>,
+    },
+    /// The downloads enqueued since the daemon started, in the order they were enqueued.
+    Jobs { jobs: Vec<JobInfo> },
+    /// The request failed.
+    Error { message: String },
+}
+
+impl Response {
+    pub(crate) fn error(message: impl Into<String>) -> Self {
+        Self::Error {
+            message: message.into(),
+        }
+    }
+}
+
+/// A download that was just enqueued.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct EnqueuedJob {
+    /// The ID used to refer to the download in later requests.
+    pub(crate) id: u64,
+    pub(crate) url: Url,
+    pub(crate) path: Utf8PathBuf,
+}
+
+/// A download known to the daemon.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct JobInfo {
+    pub(crate) id: u64,
+    pub(crate) url: Url,
+    pub(crate) path: Utf8PathBuf,
+    /// The state recorded in the database, or null if the download hasn't been queued yet.
+    pub(crate) state: Option<DownloadState>,
+    /// True if the download was paused through the daemon.
+    pub(crate) paused: bool,
+    /// True once the download's worker has exited, whether or not it succeeded.
+    pub(crate) finished: bool,
+    pub(crate) bytes_downloaded: u64,
+    /// The size of the whole file, or null if it isn't known.
+    pub(crate) total_bytes: Option<u64>,
+    pub(crate) last_error: Option<String>,
+}
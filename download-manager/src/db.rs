@@ -1,40 +1,445 @@
-//! A really basic database that stores its result in memory, using a manager task and handles to
-//! communicate with it.
+//! A really basic database that stores its results in a JSON file in the output directory, using a
+//! manager task and handles to communicate with it.
 //!
-//! A production implementation would likely use an embedded database like SQLite or even a JSON
-//! file.
+//! The whole database is rewritten to a temporary file that is then atomically renamed over the old
+//! one. That means a crash at any point leaves either the old or the new version on disk, never a
+//! torn write. Since each write costs as much as the whole database, changes are batched: changes
+//! to the state of a download are written (and fsynced) within `STATE_FLUSH_DELAY`, and progress
+//! updates within `PROGRESS_FLUSH_DELAY`, without an fsync. Callers that need changes on disk
+//! sooner, e.g. before the process is stopped, can ask for them to be flushed right away. A
+//! production implementation with many thousands of downloads would likely use an embedded
+//! database like SQLite instead.
 
-use std::fmt;
-use tokio::sync::{mpsc, oneshot};
+use crate::{
+    checksum::{Checksum, ChecksumAlgorithm},
+    command::CancelKind,
+    events::{Event, Events},
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use eyre::{bail, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    os::fd::AsRawFd,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
 use url::Url;
 
+/// The name of the database file, stored within the output directory.
+pub(crate) const DB_FILE_NAME: &str = ".download-manager.json";
+
+/// The name of the lock file held by the process using the database, stored within the output
+/// directory.
+pub(crate) const LOCK_FILE_NAME: &str = ".download-manager.lock";
+
+/// The current version of the on-disk format.
+const DB_VERSION: u32 = 1;
+
+/// How long a change to the state of a download can wait before it's written to disk.
+const STATE_FLUSH_DELAY: Duration = Duration::from_millis(100);
+
+/// How long a progress update can wait before it's written to disk.
+const PROGRESS_FLUSH_DELAY: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub(crate) struct DatabaseTask {
+    path: Utf8PathBuf,
+    contents: DbContents,
     receiver: mpsc::Receiver<DatabaseMessage>,
+    events: Events,
+    /// When unwritten changes must be written by, or `None` if there aren't any.
+    flush_at: Option<Instant>,
+    /// Whether the next write must be fsynced, because something other than progress changed.
+    needs_sync: bool,
+    /// The lock file, locked for as long as the task is alive.
+    _lock: fs_err::File,
 }
 
 impl DatabaseTask {
-    pub(crate) fn new() -> (Self, DbWorkerHandle) {
+    /// Loads the database from `out_dir`, creating a new one if it doesn't exist.
+    ///
+    /// Changes to the state of downloads are reported to `events`.
+    ///
+    /// Only one process can use the database at a time, so this fails if another process has
+    /// already loaded it.
+    pub(crate) async fn load(out_dir: &Utf8Path, events: Events) -> Result<(Self, DbWorkerHandle)> {
+        let lock = lock_out_dir(out_dir)?;
+        let path = out_dir.join(DB_FILE_NAME);
+        let mut contents = DbContents::read(&path).await?;
+
+        // Any downloads still marked as in progress belong to a previous process that exited
+        // without cleaning up (e.g. it crashed or was SIGKILLed). Mark them as interrupted.
+        for (url, record) in &mut contents.downloads {
+            if matches!(
+                record.state,
+                DownloadState::Queued
+                    | DownloadState::Downloading
+                    | DownloadState::Retrying
+                    | DownloadState::Paused
+            ) {
+                tracing::info!(url = %url, "marking stale download as interrupted");
+                record.state = DownloadState::Interrupted;
+            }
+        }
+
+        tracing::debug!(
+            path = %path,
+            entries = contents.downloads.len(),
+            "loaded database",
+        );
+
         let (sender, receiver) = mpsc::channel(16);
-        (Self { receiver }, DbWorkerHandle { sender })
+        Ok((
+            Self {
+                path,
+                contents,
+                receiver,
+                events,
+                flush_at: None,
+                needs_sync: false,
+                _lock: lock,
+            },
+            DbWorkerHandle { sender },
+        ))
     }
 
     pub(crate) async fn run(mut self) {
-        // This is the main loop that implements the database task.
+        // This is the main loop that implements the database task. Changes are written out by
+        // the flush timer, or once every handle has been dropped.
         loop {
-            match self.receiver.recv().await {
+            let message = tokio::select! {
+                message = self.receiver.recv() => message,
+                () = tokio::time::sleep_until(self.flush_at.unwrap_or_else(Instant::now)),
+                    if self.flush_at.is_some() =>
+                {
+                    self.flush().await;
+                    continue;
+                }
+            };
+            match message {
+                Some(DatabaseMessage::Queue(url, path, sender)) => {
+                    tracing::debug!(url = %url, path = %path, "queueing download in database");
+                    let now = SystemTime::now();
+                    let previous = self.state(&url);
+                    // Keep any existing record around, since it's needed to resume the download.
+                    let record = self
+                        .contents
+                        .downloads
+                        .entry(url.clone())
+                        .or_insert_with(|| DownloadRecord {
+                            state: DownloadState::Queued,
+                            path: path.clone(),
+                            temp_path: None,
+                            bytes_downloaded: 0,
+                            total_bytes: None,
+                            segments: Vec::new(),
+                            attempt: 0,
+                            mirror: None,
+                            validators: Validators::default(),
+                            checksums: BTreeMap::new(),
+                            interrupted_by: None,
+                            last_error: None,
+                            started_at: now,
+                            updated_at: now,
+                            finished_at: None,
+                        });
+                    record.state = DownloadState::Queued;
+                    record.updated_at = now;
+                    self.mark_dirty(true);
+                    self.state_changed(&url, previous, DownloadState::Queued);
+                    _ = sender.send(());
+                }
+                Some(DatabaseMessage::Start(
+                    url,
+                    path,
+                    temp_path,
+                    resume_from,
+                    attempt,
+                    mirror,
+                    sender,
+                )) => {
+                    tracing::debug!(
+                        url = %url,
+                        path = %path,
+                        temp_path = %temp_path,
+                        resume_from,
+                        attempt,
+                        mirror = mirror.as_ref().map(tracing::field::display),
+                        "starting download in database",
+                    );
+                    let now = SystemTime::now();
+                    let previous = self.contents.downloads.get(&url);
+                    let previous_state = previous.map(|record| record.state);
+                    // For retries, keep the time the first attempt started.
+                    let started_at = match previous {
+                        Some(record) if attempt > 1 => record.started_at,
+                        _ => now,
+                    };
+                    // When resuming, keep the validators and segments that the partial file was
+                    // downloaded with, since they're needed to resume it again. Validators only
+                    // apply to the server that sent them, though.
+                    let (validators, segments) = match previous {
+                        Some(record) if resume_from > 0 => {
+                            let validators = if record.mirror == mirror {
+                                record.validators.clone()
+                            } else {
+                                Validators::default()
+                            };
+                            (validators, record.segments.clone())
+                        }
+                        _ => (Validators::default(), Vec::new()),
+                    };
+                    let total_bytes = previous
+                        .and_then(|record| record.total_bytes)
+                        .filter(|_| resume_from > 0);
+                    self.contents.downloads.insert(
+                        url.clone(),
+                        DownloadRecord {
+                            state: DownloadState::Downloading,
+                            path,
+                            temp_path: Some(temp_path),
+                            bytes_downloaded: resume_from,
+                            total_bytes,
+                            segments,
+                            attempt,
+                            mirror,
+                            validators,
+                            checksums: BTreeMap::new(),
+                            interrupted_by: None,
+                            last_error: None,
+                            started_at,
+                            updated_at: now,
+                            finished_at: None,
+                        },
+                    );
+                    self.mark_dirty(true);
+                    self.state_changed(&url, previous_state, DownloadState::Downloading);
+                    _ = sender.send(());
+                }
+                Some(DatabaseMessage::Get(url, sender)) => {
+                    _ = sender.send(self.contents.downloads.get(&url).cloned());
+                }
+                Some(DatabaseMessage::List(urls, sender)) => {
+                    let records = urls
+                        .into_iter()
+                        .filter_map(|url| {
+                            let record = self.contents.downloads.get(&url)?.clone();
+                            Some((url, record))
+                        })
+                        .collect();
+                    _ = sender.send(records);
+                }
                 Some(DatabaseMessage::UpdateState(url, state, sender)) => {
                     tracing::info!(url = %url, state = ?state, "updating state in database");
-                    // This is where you'd write to a file if desired.
+                    if let Some(record) = self.contents.downloads.get_mut(&url) {
+                        let now = SystemTime::now();
+                        let previous = record.state;
+                        record.state = state;
+                        record.updated_at = now;
+                        if state.is_finished() {
+                            record.finished_at = Some(now);
+                        }
+                        self.mark_dirty(true);
+                        self.state_changed(&url, Some(previous), state);
+                    } else {
+                        tracing::warn!(url = %url, "state update for unknown download");
+                    }
+                    _ = sender.send(());
+                }
+                Some(DatabaseMessage::Complete(url, checksums, sender)) => {
+                    tracing::info!(url = %url, "marking download as completed in database");
+                    if let Some(record) = self.contents.downloads.get_mut(&url) {
+                        let now = SystemTime::now();
+                        let previous = record.state;
+                        record.state = DownloadState::Completed;
+                        // The temporary file has been renamed into place.
+                        record.temp_path = None;
+                        record.segments.clear();
+                        record.checksums = checksums
+                            .into_iter()
+                            .map(|checksum| (checksum.algorithm, checksum.expected))
+                            .collect();
+                        record.updated_at = now;
+                        record.finished_at = Some(now);
+                        self.mark_dirty(true);
+                        self.state_changed(&url, Some(previous), DownloadState::Completed);
+                    }
+                    _ = sender.send(());
+                }
+                Some(DatabaseMessage::MarkFailed(url, state, error, sender)) => {
+                    tracing::info!(
+                        url = %url,
+                        state = ?state,
+                        error = %error,
+                        "marking download as failed in database",
+                    );
+                    if let Some(record) = self.contents.downloads.get_mut(&url) {
+                        let now = SystemTime::now();
+                        let previous = record.state;
+                        record.state = state;
+                        record.last_error = Some(error);
+                        record.updated_at = now;
+                        if state.is_finished() {
+                            record.finished_at = Some(now);
+                        }
+                        if state == DownloadState::Corrupt {
+                            // The temporary file has been moved to quarantine.
+                            record.temp_path = None;
+                            record.segments.clear();
+                        }
+                        self.mark_dirty(true);
+                        self.state_changed(&url, Some(previous), state);
+                    }
+                    _ = sender.send(());
+                }
+                Some(DatabaseMessage::MarkInterrupted(url, kind, sender)) => {
+                    tracing::info!(
+                        url = %url,
+                        reason = ?kind,
+                        "marking download as interrupted in database",
+                    );
+                    if let Some(record) = self.contents.downloads.get_mut(&url) {
+                        let now = SystemTime::now();
+                        let previous = record.state;
+                        record.state = DownloadState::Interrupted;
+                        record.interrupted_by = Some(kind);
+                        record.updated_at = now;
+                        record.finished_at = Some(now);
+                        self.mark_dirty(true);
+                        self.state_changed(&url, Some(previous), DownloadState::Interrupted);
+                    }
+                    _ = sender.send(());
+                }
+                Some(DatabaseMessage::UpdateResponse(url, validators, total_bytes, sender)) => {
+                    if let Some(record) = self.contents.downloads.get_mut(&url) {
+                        record.validators = validators;
+                        record.total_bytes = total_bytes;
+                        record.updated_at = SystemTime::now();
+                        self.mark_dirty(true);
+                    }
+                    _ = sender.send(());
+                }
+                Some(DatabaseMessage::UpdateSegments(url, segments, sender)) => {
+                    if let Some(record) = self.contents.downloads.get_mut(&url) {
+                        record.bytes_downloaded = segments.iter().map(|s| s.downloaded).sum();
+                        record.segments = segments;
+                        record.updated_at = SystemTime::now();
+                        self.mark_dirty(false);
+                    }
+                    _ = sender.send(());
+                }
+                Some(DatabaseMessage::UpdateProgress(url, bytes_downloaded, sender)) => {
+                    if let Some(record) = self.contents.downloads.get_mut(&url) {
+                        record.bytes_downloaded = bytes_downloaded;
+                        record.updated_at = SystemTime::now();
+                        self.mark_dirty(false);
+                    }
+                    _ = sender.send(());
+                }
+                Some(DatabaseMessage::Flush(sender)) => {
+                    // The caller is relying on the changes surviving, so fsync them even if only
+                    // progress has changed.
+                    if self.flush_at.is_some() {
+                        self.needs_sync = true;
+                    }
+                    self.flush().await;
                     _ = sender.send(());
                 }
                 None => {
+                    self.flush().await;
                     tracing::info!("no more senders, database task shutting down");
                     break;
                 }
             }
         }
     }
+
+    /// Returns the current state of the download for `url`, if it's in the database.
+    fn state(&self, url: &Url) -> Option<DownloadState> {
+        self.contents.downloads.get(url).map(|record| record.state)
+    }
+
+    /// Emits an event if a download's state changed.
+    fn state_changed(&self, url: &Url, previous: Option<DownloadState>, state: DownloadState) {
+        if previous != Some(state) {
+            self.events.emit(Event::StateChanged {
+                url,
+                previous,
+                state,
+            });
+        }
+    }
+
+    /// Schedules a write of the database. `state` is true if something other than progress
+    /// changed, in which case the write happens sooner and is fsynced.
+    fn mark_dirty(&mut self, state: bool) {
+        let delay = if state {
+            STATE_FLUSH_DELAY
+        } else {
+            PROGRESS_FLUSH_DELAY
+        };
+        let deadline = Instant::now() + delay;
+        self.flush_at = Some(self.flush_at.map_or(deadline, |at| at.min(deadline)));
+        self.needs_sync |= state;
+    }
+
+    /// Writes the database to disk, if anything has changed since the last write.
+    ///
+    /// Errors are logged rather than returned: the in-memory state stays authoritative for this
+    /// process, and the next successful write will catch the file up.
+    async fn flush(&mut self) {
+        if self.flush_at.take().is_none() {
+            return;
+        }
+        let sync = std::mem::take(&mut self.needs_sync);
+        if let Err(error) = self.contents.write(&self.path, sync).await {
+            tracing::error!(error = ?error, path = %self.path, "failed to persist database");
+        }
+    }
+}
+
+/// Takes an exclusive advisory lock on the database in `out_dir`, so that two processes don't
+/// overwrite each other's records, or delete files the other is downloading to.
+///
+/// The lock is held until the returned file is closed, or the process exits.
+pub(crate) fn lock_out_dir(out_dir: &Utf8Path) -> Result<fs_err::File> {
+    // The database file itself is replaced on every write, so the lock is taken on a separate file.
+    let path = out_dir.join(LOCK_FILE_NAME);
+    let file = fs_err::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(&path)?;
+    // SAFETY: flock has no memory safety preconditions, and the file descriptor stays valid for the
+    // duration of the call.
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret != 0 {
+        let error = io::Error::last_os_error();
+        if error.kind() == io::ErrorKind::WouldBlock {
+            bail!(
+                "output directory `{out_dir}` is in use by another download-manager process \
+                 (`{path}` is locked)"
+            );
+        }
+        return Err(error).wrap_err_with(|| format!("failed to lock `{path}`"));
+    }
+    Ok(file)
+}
+
+/// Reads the records stored in `out_dir` by previous runs, without starting a database task.
+///
+/// This can be called while another process is downloading to `out_dir`, since the database file
+/// is always replaced atomically.
+pub(crate) async fn read_records(out_dir: &Utf8Path) -> Result<BTreeMap<Url, DownloadRecord>> {
+    Ok(DbContents::read(&out_dir.join(DB_FILE_NAME))
+        .await?
+        .downloads)
 }
 
 #[derive(Debug, Clone)]
@@ -43,6 +448,59 @@ pub(crate) struct DbWorkerHandle {
 }
 
 impl DbWorkerHandle {
+    /// Returns the record for a download, if one exists.
+    ///
+    /// This will return an error if the download task dies for some reason.
+    pub(crate) async fn get(&self, url: Url) -> Result<Option<DownloadRecord>, DbTaskDead> {
+        self.request(|sender| DatabaseMessage::Get(url, sender))
+            .await
+    }
+
+    /// Returns the records for several downloads at once. URLs that aren't in the database are
+    /// left out.
+    ///
+    /// This will return an error if the download task dies for some reason.
+    pub(crate) async fn list(
+        &self,
+        urls: Vec<Url>,
+    ) -> Result<BTreeMap<Url, DownloadRecord>, DbTaskDead> {
+        self.request(|sender| DatabaseMessage::List(urls, sender))
+            .await
+    }
+
+    /// Records that a download to `path` is waiting to start.
+    ///
+    /// The previous record for the URL, if any, is kept apart from its state.
+    ///
+    /// This will return an error if the download task dies for some reason.
+    pub(crate) async fn queue(&self, url: Url, path: Utf8PathBuf) -> Result<(), DbTaskDead> {
+        self.request(|sender| DatabaseMessage::Queue(url, path, sender))
+            .await
+    }
+
+    /// Records that a download to `path` is starting, replacing any previous record for the URL.
+    ///
+    /// Data is written to `temp_path` until the download completes. `resume_from` is the number of
+    /// bytes already present in `temp_path`, or 0 for a fresh download.
+    /// `attempt` starts at 1, and is incremented for each retry. `mirror` is the mirror the data
+    /// is being fetched from, or `None` if it's being fetched from `url` itself.
+    ///
+    /// This will return an error if the download task dies for some reason.
+    pub(crate) async fn start(
+        &self,
+        url: Url,
+        path: Utf8PathBuf,
+        temp_path: Utf8PathBuf,
+        resume_from: u64,
+        attempt: u32,
+        mirror: Option<Url>,
+    ) -> Result<(), DbTaskDead> {
+        self.request(|sender| {
+            DatabaseMessage::Start(url, path, temp_path, resume_from, attempt, mirror, sender)
+        })
+        .await
+    }
+
     /// Updates the state of a download.
     ///
     /// This will return an error if the download task dies for some reason.
@@ -51,13 +509,109 @@ impl DbWorkerHandle {
         url: Url,
         state: DownloadState,
     ) -> Result<(), DbTaskDead> {
+        self.request(|sender| DatabaseMessage::UpdateState(url, state, sender))
+            .await
+    }
+
+    /// Marks a download as completed, recording the checksums it was verified against.
+    ///
+    /// This will return an error if the download task dies for some reason.
+    pub(crate) async fn complete(
+        &self,
+        url: Url,
+        checksums: Vec<Checksum>,
+    ) -> Result<(), DbTaskDead> {
+        self.request(|sender| DatabaseMessage::Complete(url, checksums, sender))
+            .await
+    }
+
+    /// Marks a download as failed, recording the error that caused it.
+    ///
+    /// `state` is the failure state to record, e.g. [`DownloadState::TimedOut`], or
+    /// [`DownloadState::Retrying`] if the download will be retried.
+    ///
+    /// This will return an error if the download task dies for some reason.
+    pub(crate) async fn mark_failed(
+        &self,
+        url: Url,
+        state: DownloadState,
+        error: String,
+    ) -> Result<(), DbTaskDead> {
+        self.request(|sender| DatabaseMessage::MarkFailed(url, state, error, sender))
+            .await
+    }
+
+    /// Marks a download as interrupted, recording the signal or request that caused it.
+    ///
+    /// This will return an error if the download task dies for some reason.
+    pub(crate) async fn mark_interrupted(
+        &self,
+        url: Url,
+        kind: CancelKind,
+    ) -> Result<(), DbTaskDead> {
+        self.request(|sender| DatabaseMessage::MarkInterrupted(url, kind, sender))
+            .await
+    }
+
+    /// Records what the server said about a download's file: its validators, and its size if
+    /// known.
+    ///
+    /// This will return an error if the download task dies for some reason.
+    pub(crate) async fn update_response(
+        &self,
+        url: Url,
+        validators: Validators,
+        total_bytes: Option<u64>,
+    ) -> Result<(), DbTaskDead> {
+        self.request(|sender| DatabaseMessage::UpdateResponse(url, validators, total_bytes, sender))
+            .await
+    }
+
+    /// Updates the number of bytes downloaded so far.
+    ///
+    /// This will return an error if the download task dies for some reason.
+    pub(crate) async fn update_progress(
+        &self,
+        url: Url,
+        bytes_downloaded: u64,
+    ) -> Result<(), DbTaskDead> {
+        self.request(|sender| DatabaseMessage::UpdateProgress(url, bytes_downloaded, sender))
+            .await
+    }
+
+    /// Updates the segments of a segmented download, along with the total number of bytes
+    /// downloaded.
+    ///
+    /// This will return an error if the download task dies for some reason.
+    pub(crate) async fn update_segments(
+        &self,
+        url: Url,
+        segments: Vec<Segment>,
+    ) -> Result<(), DbTaskDead> {
+        self.request(|sender| DatabaseMessage::UpdateSegments(url, segments, sender))
+            .await
+    }
+
+    /// Writes any changes that are waiting to be written to disk right away, and fsyncs them.
+    ///
+    /// Changes are otherwise written after a short delay, so this should be called before doing
+    /// anything that could stop them from being written, like stopping the process.
+    ///
+    /// This will return an error if the download task dies for some reason.
+    pub(crate) async fn flush(&self) -> Result<(), DbTaskDead> {
+        self.request(DatabaseMessage::Flush).await
+    }
+
+    async fn request<T>(
+        &self,
+        f: impl FnOnce(oneshot::Sender<T>) -> DatabaseMessage,
+    ) -> Result<T, DbTaskDead> {
         let (sender, receiver) = oneshot::channel();
         self.sender
-            .send(DatabaseMessage::UpdateState(url, state, sender))
+            .send(f(sender))
             .await
             .map_err(|_| DbTaskDead {})?;
-        receiver.await.map_err(|_| DbTaskDead {})?;
-        Ok(())
+        receiver.await.map_err(|_| DbTaskDead {})
     }
 }
 
@@ -78,18 +632,295 @@ impl std::error::Error for DbTaskDead {}
 /// request to the database task.
 #[derive(Debug)]
 enum DatabaseMessage {
+    /// Get the record for a download.
+    Get(Url, oneshot::Sender<Option<DownloadRecord>>),
+    /// Get the records for several downloads.
+    List(Vec<Url>, oneshot::Sender<BTreeMap<Url, DownloadRecord>>),
+    /// Record that a download is waiting to start.
+    Queue(Url, Utf8PathBuf, oneshot::Sender<()>),
+    /// Start tracking an attempt to download to the given path via the given temporary path,
+    /// resuming from the given offset, optionally from a mirror.
+    Start(
+        Url,
+        Utf8PathBuf,
+        Utf8PathBuf,
+        u64,
+        u32,
+        Option<Url>,
+        oneshot::Sender<()>,
+    ),
     /// Update the state of a download.
     UpdateState(Url, DownloadState, oneshot::Sender<()>),
+    /// Mark a download as completed, having been verified against the given checksums.
+    Complete(Url, Vec<Checksum>, oneshot::Sender<()>),
+    /// Mark a download as failed with an error.
+    MarkFailed(Url, DownloadState, String, oneshot::Sender<()>),
+    /// Mark a download as interrupted by a signal or a request to the daemon.
+    MarkInterrupted(Url, CancelKind, oneshot::Sender<()>),
+    /// Update the validators and size returned by the server.
+    UpdateResponse(Url, Validators, Option<u64>, oneshot::Sender<()>),
+    /// Update the segments of a segmented download.
+    UpdateSegments(Url, Vec<Segment>, oneshot::Sender<()>),
+    /// Update the number of bytes downloaded.
+    UpdateProgress(Url, u64, oneshot::Sender<()>),
+    /// Write any pending changes to disk right away.
+    Flush(oneshot::Sender<()>),
+}
+
+/// The on-disk representation of the database.
+#[derive(Debug, Serialize, Deserialize)]
+struct DbContents {
+    version: u32,
+    downloads: BTreeMap<Url, DownloadRecord>,
+}
+
+impl DbContents {
+    async fn read(path: &Utf8Path) -> Result<Self> {
+        let contents = match fs_err::tokio::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self {
+                    version: DB_VERSION,
+                    downloads: BTreeMap::new(),
+                });
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let db: Self = serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("failed to parse database at `{path}`"))?;
+        if db.version != DB_VERSION {
+            eyre::bail!(
+                "database at `{path}` has unsupported version {} (expected {DB_VERSION})",
+                db.version,
+            );
+        }
+        Ok(db)
+    }
+
+    /// Writes the database to `path`, fsyncing it first if `sync` is true.
+    async fn write(&self, path: &Utf8Path, sync: bool) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+
+        // Write to a temporary file (and fsync it), then rename it over the real file.
+        let mut tmp_path = path.to_owned();
+        tmp_path.set_extension("json.tmp");
+        let mut f = fs_err::tokio::File::create(&tmp_path).await?;
+        f.write_all(&json).await?;
+        // Tokio writes to files in the background, so this waits for the write to finish.
+        f.flush().await?;
+        if sync {
+            f.sync_all().await?;
+        }
+        std::mem::drop(f);
+        fs_err::tokio::rename(&tmp_path, path).await?;
+
+        Ok(())
+    }
+}
+
+/// The information stored about a single download.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DownloadRecord {
+    pub(crate) state: DownloadState,
+    /// The path the download is being written to.
+    pub(crate) path: Utf8PathBuf,
+    /// The temporary file that data is written to until the download completes, at which point
+    /// it's renamed to `path`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) temp_path: Option<Utf8PathBuf>,
+    /// The number of bytes downloaded so far.
+    pub(crate) bytes_downloaded: u64,
+    /// The size of the whole file, if the server said what it is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) total_bytes: Option<u64>,
+    /// For segmented downloads, the progress of each segment. This is empty for downloads that use
+    /// a single connection.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) segments: Vec<Segment>,
+    /// The current attempt number, starting from 1. This is 0 if the download hasn't started yet.
+    #[serde(default)]
+    pub(crate) attempt: u32,
+    /// The mirror the data is being (or was) downloaded from, if it isn't the download's own URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mirror: Option<Url>,
+    #[serde(flatten)]
+    pub(crate) validators: Validators,
+    /// The checksums the completed file was verified against, as lowercase hex.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) checksums: BTreeMap<ChecksumAlgorithm, String>,
+    /// If the download was interrupted, the signal (or request) that caused it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) interrupted_by: Option<CancelKind>,
+    /// If the download failed, the error that caused it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_error: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) started_at: SystemTime,
+    #[serde(with = "humantime_serde")]
+    pub(crate) updated_at: SystemTime,
+    #[serde(with = "humantime_serde", default)]
+    pub(crate) finished_at: Option<SystemTime>,
+}
+
+/// A contiguous range of a segmented download, fetched over its own connection.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct Segment {
+    /// The offset of the first byte in the segment.
+    pub(crate) start: u64,
+    /// The offset one past the last byte in the segment.
+    pub(crate) end: u64,
+    /// The number of bytes downloaded from the start of the segment.
+    pub(crate) downloaded: u64,
+}
+
+impl Segment {
+    /// Returns the offset that downloading should continue from.
+    pub(crate) fn position(&self) -> u64 {
+        self.start + self.downloaded
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.position() >= self.end
+    }
+}
+
+/// HTTP validators for a download, used to check that a partial file still matches what the
+/// server would send.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Returns the value to send in an `If-Range` header, if any.
+    ///
+    /// Weak ETags can't be used with `If-Range`, so fall back to `Last-Modified` in that case.
+    pub(crate) fn if_range(&self) -> Option<&str> {
+        match &self.etag {
+            Some(etag) if !etag.starts_with("W/") => Some(etag),
+            _ => self.last_modified.as_deref(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) enum DownloadState {
+    /// The download is waiting for a free slot, as limited by `--jobs` and per-host limits.
+    Queued,
     /// The download is in progress.
     Downloading,
+    /// An attempt failed with a transient error, and the download will be retried.
+    Retrying,
+    /// The download was paused by SIGTSTP.
+    Paused,
     /// The download is complete.
     Completed,
     /// The download failed.
     Failed,
+    /// The downloaded file didn't match its expected checksum, and was moved to the quarantine
+    /// directory.
+    Corrupt,
+    /// The download timed out, either because the server stopped sending data or because it took
+    /// too long overall.
+    TimedOut,
     /// The download was interrupted.
     Interrupted,
 }
+
+impl DownloadState {
+    /// Returns true if this is a terminal state for a download in this process.
+    pub(crate) fn is_finished(self) -> bool {
+        match self {
+            Self::Queued | Self::Downloading | Self::Retrying | Self::Paused => false,
+            Self::Completed | Self::Failed | Self::Corrupt | Self::TimedOut | Self::Interrupted => {
+                true
+            }
+        }
+    }
+}
+
+impl fmt::Display for DownloadState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Queued => "queued",
+            Self::Downloading => "downloading",
+            Self::Retrying => "retrying",
+            Self::Paused => "paused",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+            Self::Corrupt => "corrupt",
+            Self::TimedOut => "timed-out",
+            Self::Interrupted => "interrupted",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::MessageFormat;
+
+    /// A fresh directory within the system's temporary directory, removed when dropped.
+    struct TempDir(Utf8PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = Utf8PathBuf::try_from(std::env::temp_dir())
+                .unwrap()
+                .join(format!("download-manager-{name}-{}", std::process::id()));
+            _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn flush_writes_paused_state() {
+        let dir = TempDir::new("flush");
+        let (task, handle) = DatabaseTask::load(&dir.0, Events::new(MessageFormat::Human))
+            .await
+            .unwrap();
+        let task = tokio::spawn(task.run());
+
+        // This is what a download does when it's paused on SIGTSTP.
+        let url = Url::parse("https://example.com/a.iso").unwrap();
+        let path = dir.0.join("a.iso");
+        handle.queue(url.clone(), path.clone()).await.unwrap();
+        handle
+            .start(
+                url.clone(),
+                path.clone(),
+                dir.0.join("a.iso.part"),
+                0,
+                1,
+                None,
+            )
+            .await
+            .unwrap();
+        handle.update_progress(url.clone(), 1024).await.unwrap();
+        handle
+            .update_state(url.clone(), DownloadState::Paused)
+            .await
+            .unwrap();
+
+        // Once the flush returns, the state must be on disk, even though the handle is still alive
+        // and so the task hasn't shut down.
+        handle.flush().await.unwrap();
+        let records = read_records(&dir.0).await.unwrap();
+        assert_eq!(records[&url].state, DownloadState::Paused);
+        assert_eq!(records[&url].bytes_downloaded, 1024);
+
+        drop(handle);
+        task.await.unwrap();
+    }
+}
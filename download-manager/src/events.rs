@@ -0,0 +1,233 @@
+//! Machine-readable events, written to standard output with `--message-format json`.
+//!
+//! Each event is a single line of JSON (so the output as a whole is newline-delimited JSON), with
+//! these fields in addition to the event's own:
+//!
+//! * `schema_version`: currently 1. This is incremented if a change might break consumers, e.g. if
+//!   a field is removed or its meaning changes. New events and new fields may be added without
+//!   changing the version, so consumers should ignore any they don't recognize.
+//! * `timestamp`: the time the event was emitted, in RFC 3339 format.
+//! * `event`: the type of event, one of the variants of [`Event`] in kebab-case.
+//!
+//! Sizes are in bytes, and durations are in milliseconds. Log lines are written to standard error
+//! instead, so that they don't get mixed up with events.
+//!
+//! The daemon sends the same events to clients that ask to watch it, rather than to standard
+//! output.
+
+use crate::db::DownloadState;
+use camino::Utf8Path;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    io::{self, Write},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::sync::broadcast;
+use url::Url;
+
+/// The version of the event schema, included with every event.
+pub(crate) const SCHEMA_VERSION: u32 = 1;
+
+/// How the download manager reports what it's doing.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub(crate) enum MessageFormat {
+    /// Log lines and progress bars, meant for people.
+    #[default]
+    Human,
+    /// Newline-delimited JSON events on standard output, with logs on standard error.
+    Json,
+}
+
+/// An event, describing something that happened during a run.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(crate) enum Event<'a> {
+    /// An attempt to download a file started.
+    Started {
+        url: &'a Url,
+        path: &'a Utf8Path,
+        /// The attempt number, starting from 1.
+        attempt: u32,
+        /// The number of bytes already downloaded by a previous attempt or run, if the download
+        /// is being resumed.
+        resume_from: u64,
+        /// The mirror being downloaded from, or null for the download's own URL.
+        mirror: Option<&'a Url>,
+    },
+    /// A download is in progress. This is emitted once a second for each active download.
+    Progress {
+        url: &'a Url,
+        bytes_downloaded: u64,
+        /// The size of the file, or null if the server didn't say.
+        total_bytes: Option<u64>,
+    },
+    /// An attempt failed with an error that might not happen again, and the download will be
+    /// retried.
+    Retry {
+        url: &'a Url,
+        /// The attempt that failed.
+        attempt: u32,
+        error: &'a str,
+        /// How long until the next attempt.
+        delay_ms: u64,
+        /// The URL the next attempt downloads from: the download's own URL, or one of its mirrors.
+        next_source: &'a Url,
+    },
+    /// The state of a download, as recorded in the database, changed.
+    StateChanged {
+        url: &'a Url,
+        /// The previous state, or null if the download wasn't in the database.
+        previous: Option<DownloadState>,
+        state: DownloadState,
+    },
+    /// A download completed.
+    Completed {
+        url: &'a Url,
+        path: &'a Utf8Path,
+        /// The mirror the file was downloaded from, or null for the download's own URL.
+        mirror: Option<&'a Url>,
+    },
+    /// A download failed, and won't be retried.
+    Failed {
+        url: &'a Url,
+        path: &'a Utf8Path,
+        error: &'a str,
+    },
+    /// A download was stopped by a signal, or cancelled through the daemon.
+    Cancelled {
+        url: &'a Url,
+        path: &'a Utf8Path,
+        /// The name of the signal, or null if the download was cancelled through the daemon.
+        signal: Option<&'static str>,
+    },
+    /// A download was skipped, because a previous run already completed it.
+    Skipped { url: &'a Url, path: &'a Utf8Path },
+    /// A signal was received.
+    SignalReceived {
+        /// The name of the signal, e.g. `SIGINT`.
+        signal: &'static str,
+        action: SignalAction,
+    },
+    /// The run, or the daemon, finished. This is always the last event.
+    Shutdown {
+        completed: usize,
+        failed: usize,
+        cancelled: usize,
+        skipped: usize,
+        /// The total number of bytes received in this run.
+        bytes: u64,
+        elapsed_ms: u64,
+        /// The signal that stopped the run, or null if it ran to completion or the daemon was asked
+        /// to shut down.
+        signal: Option<&'static str>,
+        /// True if the process exited before all downloads stopped.
+        forced: bool,
+        /// The process's exit status.
+        exit_code: u8,
+    },
+}
+
+/// What the download manager did in response to a signal.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SignalAction {
+    /// Started shutting down gracefully.
+    Shutdown,
+    /// Exited immediately, without waiting for downloads to stop.
+    Exit,
+    /// Reloaded the manifest.
+    Reload,
+    /// Paused downloads and stopped the process.
+    Pause,
+    /// Resumed downloads.
+    Resume,
+    /// Changed the global rate limit.
+    ChangeRateLimit,
+    /// Nothing, e.g. because a shutdown was already in progress.
+    Ignore,
+}
+
+/// The number of events buffered for each subscriber. Subscribers that fall further behind than
+/// this miss events.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// Writes events to standard output, if enabled by `--message-format json`, and sends them to any
+/// subscribers.
+#[derive(Clone, Debug)]
+pub(crate) struct Events {
+    stdout: bool,
+    /// Sends each event, serialized as a line of JSON, to subscribers.
+    subscribers: Option<broadcast::Sender<Arc<str>>>,
+}
+
+impl Events {
+    pub(crate) fn new(format: MessageFormat) -> Self {
+        Self {
+            stdout: format == MessageFormat::Json,
+            subscribers: None,
+        }
+    }
+
+    /// Returns an instance that doesn't write to standard output, but that can be subscribed to.
+    pub(crate) fn with_subscribers() -> Self {
+        Self {
+            stdout: false,
+            subscribers: Some(broadcast::channel(SUBSCRIBER_BUFFER).0),
+        }
+    }
+
+    /// Returns a receiver for events emitted from now on, each of which is a line of JSON
+    /// including the trailing newline.
+    ///
+    /// Returns `None` if this instance wasn't created with [`Self::with_subscribers`].
+    pub(crate) fn subscribe(&self) -> Option<broadcast::Receiver<Arc<str>>> {
+        self.subscribers.as_ref().map(broadcast::Sender::subscribe)
+    }
+
+    /// Writes `event` to standard output if enabled, and sends it to any subscribers.
+    pub(crate) fn emit(&self, event: Event<'_>) {
+        let subscribers = self
+            .subscribers
+            .as_ref()
+            .filter(|subscribers| subscribers.receiver_count() > 0);
+        if !self.stdout && subscribers.is_none() {
+            return;
+        }
+
+        #[derive(Serialize)]
+        struct Line<'a> {
+            schema_version: u32,
+            timestamp: String,
+            #[serde(flatten)]
+            event: Event<'a>,
+        }
+
+        let line = Line {
+            schema_version: SCHEMA_VERSION,
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event,
+        };
+        let mut json = serde_json::to_string(&line).expect("events can always be serialized");
+        json.push('\n');
+        if let Some(subscribers) = subscribers {
+            // An error here means that every subscriber went away in the meantime.
+            _ = subscribers.send(json.as_str().into());
+        }
+        if !self.stdout {
+            return;
+        }
+        // Write the whole line at once, so that events emitted by different tasks don't get
+        // interleaved.
+        let mut stdout = io::stdout().lock();
+        if let Err(error) = stdout
+            .write_all(json.as_bytes())
+            .and_then(|()| stdout.flush())
+        {
+            // This usually means that whatever was reading the events has exited. Keep
+            // downloading regardless.
+            tracing::debug!(error = %error, "Failed to write event");
+        }
+    }
+}
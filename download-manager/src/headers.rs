@@ -0,0 +1,99 @@
+//! Custom HTTP headers sent with download requests.
+//!
+//! Headers can be set on the command line, per host in the manifest's `[hosts]` table, and per
+//! entry (or for every entry in `[defaults]`). Values can be read from environment variables, so
+//! that secrets such as API keys don't need to be stored in the manifest.
+
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fmt};
+
+/// A header value as written in the manifest.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum HeaderSource {
+    /// A literal value.
+    Value(String),
+    /// A value read from an environment variable when the manifest is loaded.
+    Env { env: String },
+}
+
+/// Resolves headers written in the manifest, reading values from the environment as necessary.
+///
+/// Values read from the environment, and values of headers that usually carry credentials, are
+/// marked as sensitive.
+pub(crate) fn resolve(headers: &BTreeMap<String, HeaderSource>) -> Result<HeaderMap, String> {
+    let mut map = HeaderMap::new();
+    for (name, source) in headers {
+        let name: HeaderName = name
+            .parse()
+            .map_err(|_| format!("invalid header name `{name}`"))?;
+        let (value, from_env) = match source {
+            HeaderSource::Value(value) => (value.clone(), false),
+            HeaderSource::Env { env } => {
+                let value = std::env::var(env).map_err(|error| {
+                    format!("header `{name}`: environment variable `{env}`: {error}")
+                })?;
+                (value, true)
+            }
+        };
+        // Don't include the value in the error, since it may be a secret.
+        let mut value =
+            HeaderValue::from_str(&value).map_err(|_| format!("header `{name}`: invalid value"))?;
+        value.set_sensitive(from_env || is_sensitive(&name));
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+/// Parses a header passed on the command line, in the form `Name: value`.
+pub(crate) fn parse_header(input: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = input
+        .split_once(':')
+        .ok_or_else(|| "expected a header in the form `Name: value`".to_owned())?;
+    let name: HeaderName = name
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid header name `{}`", name.trim()))?;
+    let mut value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("header `{name}`: invalid value"))?;
+    value.set_sensitive(is_sensitive(&name));
+    Ok((name, value))
+}
+
+/// Returns true if headers named `name` usually carry credentials.
+fn is_sensitive(name: &HeaderName) -> bool {
+    if [
+        header::AUTHORIZATION,
+        header::PROXY_AUTHORIZATION,
+        header::COOKIE,
+    ]
+    .contains(name)
+    {
+        return true;
+    }
+    // Header names are always lowercase.
+    let name = name.as_str();
+    ["key", "token", "secret", "password"]
+        .iter()
+        .any(|word| name.contains(word))
+}
+
+/// Displays headers for logging, with sensitive values redacted.
+pub(crate) struct Redacted<'a>(pub(crate) &'a HeaderMap);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            if value.is_sensitive() {
+                write!(f, "{name}: <redacted>")?;
+            } else {
+                write!(f, "{name}: {}", String::from_utf8_lossy(value.as_bytes()))?;
+            }
+        }
+        Ok(())
+    }
+}
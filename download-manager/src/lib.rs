@@ -2,8 +2,16 @@
 //!
 //! The logic is implemented in command.rs -- head there to start.
 
+mod checksum;
 mod command;
+mod control;
 mod db;
+mod events;
+mod headers;
+mod limits;
 mod manifest;
+mod progress;
+mod rate;
+mod summary;
 
 pub use command::App;
@@ -0,0 +1,115 @@
+//! Limits on the number of concurrent downloads.
+//!
+//! Downloads are limited both globally and per host, using semaphores. A download must hold a
+//! permit from both before it starts.
+
+use crate::manifest::HostPattern;
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use url::Url;
+
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    global: Arc<Semaphore>,
+    /// The default per-host limit, if any.
+    per_host: Option<NonZeroUsize>,
+    /// Per-host limits that override the default, ordered from the least to the most specific
+    /// pattern.
+    host_overrides: Vec<(HostPattern, NonZeroUsize)>,
+    /// Semaphores for each host seen so far, created on demand.
+    host_semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl Limiter {
+    pub(crate) fn new(
+        jobs: NonZeroUsize,
+        per_host: Option<NonZeroUsize>,
+        host_overrides: Vec<(HostPattern, NonZeroUsize)>,
+    ) -> Self {
+        Self {
+            global: Arc::new(Semaphore::new(jobs.get())),
+            per_host,
+            host_overrides,
+            host_semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until a download from `url` is allowed to start.
+    ///
+    /// The download may proceed for as long as the returned permits are held.
+    pub(crate) async fn acquire(&self, url: &Url) -> Permits {
+        // Acquire the host permit first, so that downloads waiting on a busy host don't hold up
+        // global slots that downloads from other hosts could use.
+        let host = match self.host_semaphore(url) {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed"),
+            ),
+            None => None,
+        };
+        let global = self
+            .global
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+
+        Permits {
+            host: url.host_str().map(str::to_owned),
+            host_permit: host,
+            _global: global,
+        }
+    }
+
+    /// Switches `permits` over to a download from `url`, e.g. a mirror on a different host than
+    /// the one they were acquired for.
+    ///
+    /// If the host is different, the permit for the old host is released, and this waits for a
+    /// permit for the new one. The global permit is kept, since the download has already started.
+    pub(crate) async fn switch(&self, permits: &mut Permits, url: &Url) {
+        let host = url.host_str();
+        if permits.host.as_deref() == host {
+            return;
+        }
+        permits.host_permit = None;
+        permits.host = host.map(str::to_owned);
+        if let Some(semaphore) = self.host_semaphore(url) {
+            let permit = semaphore
+                .acquire_owned()
+                .await
+                .expect("semaphore is never closed");
+            permits.host_permit = Some(permit);
+        }
+    }
+
+    fn host_semaphore(&self, url: &Url) -> Option<Arc<Semaphore>> {
+        let host = url.host_str()?;
+        let limit = self
+            .host_overrides
+            .iter()
+            .rev()
+            .find_map(|(pattern, limit)| pattern.matches(host).then_some(*limit))
+            .or(self.per_host)?;
+
+        let mut host_semaphores = self.host_semaphores.lock().unwrap();
+        let semaphore = host_semaphores
+            .entry(host.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(limit.get())));
+        Some(semaphore.clone())
+    }
+}
+
+/// Permits allowing a download to proceed. Dropping this releases them.
+#[derive(Debug)]
+pub(crate) struct Permits {
+    /// The host the permits were acquired for.
+    host: Option<String>,
+    host_permit: Option<OwnedSemaphorePermit>,
+    _global: OwnedSemaphorePermit,
+}
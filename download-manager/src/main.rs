@@ -5,9 +5,10 @@
 use clap::Parser;
 use color_eyre::eyre::Result;
 use download_manager::App;
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let app = App::parse();
     app.exec().await
 }
@@ -1,28 +1,727 @@
 //! Defines the serialization and deserialization format for the manifest.
+//!
+//! The manifest is deserialized into `RawManifest`, and then resolved into a `Manifest`: each
+//! entry's options are merged with the `[defaults]` table and the command-line defaults, so that
+//! workers get a fully-specified `ManifestEntry`.
 
-use camino::Utf8Path;
-use eyre::Result;
-use serde::Deserialize;
+use crate::{
+    checksum::{Checksum, ChecksumAlgorithm},
+    headers::{self, HeaderSource},
+    rate::Rate,
+};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use eyre::{eyre, Result};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{collections::BTreeMap, fmt, num::NonZeroUsize, time::Duration};
+use tokio::io::AsyncReadExt;
 use url::Url;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub(crate) struct Manifest {
     pub(crate) downloads: Vec<ManifestEntry>,
+    /// Per-host configuration, ordered from the least to the most specific pattern.
+    pub(crate) hosts: Vec<(HostPattern, HostConfig)>,
 }
 
 impl Manifest {
-    pub(crate) async fn load(file: &Utf8Path) -> Result<Self> {
-        // We use the fs_err crate here for better error messages.
-        let contents = fs_err::tokio::read_to_string(file).await?;
-        let manifest = toml::from_str(&contents)?;
-        Ok(manifest)
+    /// Loads the manifest from `file`, or from standard input if `file` is `-`.
+    ///
+    /// If `format` isn't specified, it's detected from the file's extension, defaulting to TOML.
+    /// Options that are set neither on an entry nor in `[defaults]` are taken from `fallback`.
+    pub(crate) async fn load(
+        file: &Utf8Path,
+        format: Option<ManifestFormat>,
+        fallback: &FallbackOptions,
+    ) -> Result<Self> {
+        let format = format.unwrap_or_else(|| ManifestFormat::detect(file));
+        let contents = Self::read(file).await?;
+        Self::from_contents(&contents, format, fallback)
+            .map_err(|error| eyre!("error parsing manifest `{file}`: {error}"))
+    }
+
+    /// Loads the manifest like [`Self::load`], but without resolving headers.
+    ///
+    /// This is for commands that only look at the files a manifest describes, so that they don't
+    /// need the environment variables that header values may be read from.
+    pub(crate) async fn load_without_headers(
+        file: &Utf8Path,
+        format: Option<ManifestFormat>,
+        fallback: &FallbackOptions,
+    ) -> Result<Self> {
+        let format = format.unwrap_or_else(|| ManifestFormat::detect(file));
+        let contents = Self::read(file).await?;
+        Self::parse(&contents, format)
+            .and_then(|raw| raw.without_headers().resolve(fallback))
+            .map_err(|error| eyre!("error parsing manifest `{file}`: {error}"))
+    }
+
+    /// Reads the contents of the manifest at `file`, or from standard input if `file` is `-`.
+    pub(crate) async fn read(file: &Utf8Path) -> Result<String> {
+        if file == STDIN_PATH {
+            let mut contents = String::new();
+            tokio::io::stdin().read_to_string(&mut contents).await?;
+            Ok(contents)
+        } else {
+            // We use the fs_err crate here for better error messages.
+            Ok(fs_err::tokio::read_to_string(file).await?)
+        }
+    }
+
+    /// Parses and resolves a manifest that has already been read, e.g. one sent to the daemon.
+    pub(crate) fn from_contents(
+        contents: &str,
+        format: ManifestFormat,
+        fallback: &FallbackOptions,
+    ) -> Result<Self, String> {
+        Self::parse(contents, format).and_then(|raw| raw.resolve(fallback))
+    }
+
+    fn parse(contents: &str, format: ManifestFormat) -> Result<RawManifest, String> {
+        // serde_path_to_error tracks where in the manifest an error occurred, e.g.
+        // `downloads[2].sha256`, so that it can be reported along with the line and column.
+        fn with_path<T, E: fmt::Display>(
+            res: Result<T, serde_path_to_error::Error<E>>,
+        ) -> Result<T, String> {
+            res.map_err(|error| format!("at `{}`: {}", error.path(), error.inner()))
+        }
+
+        match format {
+            ManifestFormat::Toml => {
+                let d = toml::Deserializer::new(contents);
+                with_path(serde_path_to_error::deserialize(d))
+            }
+            ManifestFormat::Json => {
+                let mut d = serde_json::Deserializer::from_str(contents);
+                let manifest = with_path(serde_path_to_error::deserialize(&mut d))?;
+                d.end().map_err(|error| error.to_string())?;
+                Ok(manifest)
+            }
+            // serde_yaml already includes the path in its errors.
+            ManifestFormat::Yaml => {
+                serde_yaml::from_str(contents).map_err(|error| error.to_string())
+            }
+        }
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// The path that stands for standard input when passed as the manifest.
+pub(crate) const STDIN_PATH: &str = "-";
+
+/// The format a manifest is written in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ManifestFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ManifestFormat {
+    /// Detects the format from the extension of `file`.
+    pub(crate) fn detect(file: &Utf8Path) -> Self {
+        match file
+            .extension()
+            .map(|ext| ext.to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("json") => Self::Json,
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Toml,
+        }
+    }
+}
+
+/// A download, with all of its options resolved.
+#[derive(Debug)]
 pub(crate) struct ManifestEntry {
     pub(crate) url: Url,
+    /// Other URLs serving the same file, tried in order if downloading from `url` fails.
+    pub(crate) mirrors: Vec<Url>,
+    /// The path to download to, relative to the output directory.
+    pub(crate) path: Utf8PathBuf,
+    pub(crate) idle_timeout: Duration,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) retries: u32,
+    /// Checksums the downloaded file must match.
+    pub(crate) checksums: Vec<Checksum>,
+    pub(crate) segments: NonZeroUsize,
+    /// The maximum rate to download at, on top of `--limit-rate`.
+    pub(crate) limit_rate: Option<Rate>,
+    /// Headers to send with requests for this download, inherited from `[defaults]`. Headers set
+    /// for the host take precedence over these.
+    pub(crate) default_headers: HeaderMap,
+    /// Headers to send with requests for this download, set on the entry itself.
+    pub(crate) headers: HeaderMap,
+}
+
+impl ManifestEntry {
+    /// Creates an entry for a single URL, as if it were written in a manifest with no other
+    /// options.
+    ///
+    /// The file is named `file_name`, or after the last segment of the URL's path.
+    pub(crate) fn from_url(
+        url: Url,
+        file_name: Option<String>,
+        fallback: &FallbackOptions,
+    ) -> Result<Self, String> {
+        let raw = RawEntry {
+            url,
+            mirrors: Vec::new(),
+            file_name,
+            sha256: None,
+            sha512: None,
+            blake3: None,
+            checksum: None,
+            directory: None,
+            idle_timeout: None,
+            timeout: None,
+            retries: None,
+            checksum_algorithm: None,
+            segments: None,
+            limit_rate: None,
+            headers: BTreeMap::new(),
+        };
+        raw.resolve(&EntryOptions::default(), &HeaderMap::new(), fallback)
+    }
+
+    /// Returns the path this entry is downloaded to within `out_dir`.
+    pub(crate) fn out_path(&self, out_dir: &Utf8Path) -> Utf8PathBuf {
+        out_dir.join(&self.path)
+    }
+}
+
+/// Options taken from the command line, for entries that neither specify their own nor inherit
+/// them from `[defaults]`.
+#[derive(Clone, Debug)]
+pub(crate) struct FallbackOptions {
+    pub(crate) idle_timeout: Duration,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) retries: u32,
+    pub(crate) segments: NonZeroUsize,
+}
+
+impl FallbackOptions {
+    /// Options for commands that only look at the files a manifest describes, such as `verify`
+    /// and `clean`. Since only paths and checksums matter to them, the values here are arbitrary.
+    pub(crate) fn placeholder() -> Self {
+        Self {
+            idle_timeout: Duration::ZERO,
+            timeout: None,
+            retries: 0,
+            segments: NonZeroUsize::MIN,
+        }
+    }
+}
+
+/// The manifest as written, before defaults are applied.
+#[derive(Debug, Deserialize)]
+struct RawManifest {
+    /// Options inherited by every entry that doesn't override them.
+    #[serde(default)]
+    defaults: EntryOptions,
+    downloads: Vec<RawEntry>,
+    /// Per-host configuration, keyed by host pattern.
+    #[serde(default)]
+    hosts: BTreeMap<String, RawHostConfig>,
+}
+
+impl RawManifest {
+    /// Drops every header, so that resolving the manifest doesn't read any from the environment.
+    fn without_headers(mut self) -> Self {
+        self.defaults.headers.clear();
+        for entry in &mut self.downloads {
+            entry.headers.clear();
+        }
+        for config in self.hosts.values_mut() {
+            config.headers.clear();
+        }
+        self
+    }
+
+    fn resolve(self, fallback: &FallbackOptions) -> Result<Manifest, String> {
+        let default_headers = headers::resolve(&self.defaults.headers)
+            .map_err(|error| format!("at `defaults.headers`: {error}"))?;
+        let downloads = self
+            .downloads
+            .into_iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                entry
+                    .resolve(&self.defaults, &default_headers, fallback)
+                    .map_err(|error| format!("at `downloads[{index}]`: {error}"))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut hosts = self
+            .hosts
+            .into_iter()
+            .map(|(pattern, config)| {
+                let resolved = HostPattern::parse(&pattern).and_then(|pattern| {
+                    let config = HostConfig {
+                        max_connections: config.max_connections,
+                        headers: headers::resolve(&config.headers)?,
+                    };
+                    Ok((pattern, config))
+                });
+                resolved.map_err(|error| format!("at `hosts.{pattern}`: {error}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        hosts.sort_by_key(|(pattern, _)| pattern.specificity());
+
+        Ok(Manifest { downloads, hosts })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RawEntry {
+    url: Url,
+    #[serde(default)]
+    mirrors: Vec<Url>,
+    #[serde(default)]
+    file_name: Option<String>,
+    /// The expected SHA-256 checksum of the file, as hex.
+    #[serde(default, deserialize_with = "deserialize_sha256")]
+    sha256: Option<Checksum>,
+    /// The expected SHA-512 checksum of the file, as hex.
+    #[serde(default, deserialize_with = "deserialize_sha512")]
+    sha512: Option<Checksum>,
+    /// The expected BLAKE3 checksum of the file, as hex.
+    #[serde(default, deserialize_with = "deserialize_blake3")]
+    blake3: Option<Checksum>,
+    /// The expected checksum of the file, as hex, using `checksum_algorithm`.
+    #[serde(default)]
+    checksum: Option<String>,
+
+    // These fields are the same as in EntryOptions. (They aren't flattened into this struct, since
+    // with #[serde(flatten)] errors no longer point at the field.)
+    #[serde(default)]
+    directory: Option<Utf8PathBuf>,
+    #[serde(default, with = "humantime_serde")]
+    idle_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    timeout: Option<Duration>,
+    #[serde(default)]
+    retries: Option<u32>,
+    #[serde(default)]
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    #[serde(default)]
+    segments: Option<NonZeroUsize>,
+    #[serde(default)]
+    limit_rate: Option<Rate>,
+    #[serde(default)]
+    headers: BTreeMap<String, HeaderSource>,
+}
+
+impl RawEntry {
+    /// Resolves the entry. `default_headers` are the already-resolved headers from `defaults`.
+    fn resolve(
+        self,
+        defaults: &EntryOptions,
+        default_headers: &HeaderMap,
+        fallback: &FallbackOptions,
+    ) -> Result<ManifestEntry, String> {
+        let options = EntryOptions {
+            directory: self.directory,
+            idle_timeout: self.idle_timeout,
+            timeout: self.timeout,
+            retries: self.retries,
+            checksum_algorithm: self.checksum_algorithm,
+            segments: self.segments,
+            limit_rate: self.limit_rate,
+            headers: BTreeMap::new(),
+        }
+        .or(defaults);
+
+        let mut checksums: Vec<_> = [self.sha256, self.sha512, self.blake3]
+            .into_iter()
+            .flatten()
+            .collect();
+        if let Some(expected) = &self.checksum {
+            let algorithm = options
+                .checksum_algorithm
+                .unwrap_or(ChecksumAlgorithm::Sha256);
+            if checksums.iter().any(|c| c.algorithm == algorithm) {
+                return Err(format!(
+                    "both `checksum` and `{algorithm}` specify a {algorithm} checksum"
+                ));
+            }
+            checksums.push(Checksum::new(algorithm, expected)?);
+        }
+
+        let file_name = self.file_name.unwrap_or_else(|| {
+            self.url
+                .path_segments()
+                .and_then(|segments| segments.last())
+                .filter(|segment| !segment.is_empty())
+                .unwrap_or("index.html")
+                .to_string()
+        });
+        let path = match options.directory {
+            Some(directory) => directory.join(file_name),
+            None => file_name.into(),
+        };
+        // Downloads must stay within the output directory. Both `directory` and `file_name` are
+        // checked, since either can contain `..` or be absolute.
+        if path.as_str().is_empty()
+            || !path
+                .components()
+                .all(|component| matches!(component, Utf8Component::Normal(_)))
+        {
+            return Err(format!(
+                "path `{path}` (from `directory` and `file_name`) must be a relative path \
+                 without `..`"
+            ));
+        }
+
+        // Headers inherited from `[defaults]` are kept separate, since headers set for the host take
+        // precedence over them.
+        let headers = headers::resolve(&self.headers)?;
+
+        Ok(ManifestEntry {
+            url: self.url,
+            mirrors: self.mirrors,
+            path,
+            idle_timeout: options.idle_timeout.unwrap_or(fallback.idle_timeout),
+            timeout: options.timeout.or(fallback.timeout),
+            retries: options.retries.unwrap_or(fallback.retries),
+            checksums,
+            segments: options.segments.unwrap_or(fallback.segments),
+            limit_rate: options.limit_rate,
+            default_headers: default_headers.clone(),
+            headers,
+        })
+    }
+}
+
+/// Options that can be set on an entry, or in `[defaults]` for every entry.
+#[derive(Clone, Debug, Default, Deserialize)]
+struct EntryOptions {
+    /// The subdirectory of the output directory to download into.
+    #[serde(default)]
+    directory: Option<Utf8PathBuf>,
+    /// Overrides `--idle-timeout`.
+    #[serde(default, with = "humantime_serde")]
+    idle_timeout: Option<Duration>,
+    /// Overrides `--timeout`.
+    #[serde(default, with = "humantime_serde")]
+    timeout: Option<Duration>,
+    /// Overrides `--retries`.
+    #[serde(default)]
+    retries: Option<u32>,
+    /// The algorithm used for `checksum` [default: sha256].
+    #[serde(default)]
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    /// Overrides `--segments`.
+    #[serde(default)]
+    segments: Option<NonZeroUsize>,
+    /// The maximum rate to download at, in bytes per second or as a string like `500K`.
     #[serde(default)]
-    pub(crate) file_name: Option<String>,
+    limit_rate: Option<Rate>,
+    /// Headers to send with requests.
+    #[serde(default)]
+    headers: BTreeMap<String, HeaderSource>,
     // Other options can go here
 }
+
+impl EntryOptions {
+    /// Fills in options that aren't set with those from `defaults`.
+    fn or(self, defaults: &EntryOptions) -> EntryOptions {
+        let defaults = defaults.clone();
+        EntryOptions {
+            directory: self.directory.or(defaults.directory),
+            idle_timeout: self.idle_timeout.or(defaults.idle_timeout),
+            timeout: self.timeout.or(defaults.timeout),
+            retries: self.retries.or(defaults.retries),
+            checksum_algorithm: self.checksum_algorithm.or(defaults.checksum_algorithm),
+            segments: self.segments.or(defaults.segments),
+            limit_rate: self.limit_rate.or(defaults.limit_rate),
+            // Headers are resolved separately, since headers set for the host sit between the
+            // defaults and the entry's own.
+            headers: self.headers,
+        }
+    }
+}
+
+fn deserialize_sha256<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Checksum>, D::Error> {
+    deserialize_checksum(d, ChecksumAlgorithm::Sha256)
+}
+
+fn deserialize_sha512<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Checksum>, D::Error> {
+    deserialize_checksum(d, ChecksumAlgorithm::Sha512)
+}
+
+fn deserialize_blake3<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Checksum>, D::Error> {
+    deserialize_checksum(d, ChecksumAlgorithm::Blake3)
+}
+
+fn deserialize_checksum<'de, D: Deserializer<'de>>(
+    d: D,
+    algorithm: ChecksumAlgorithm,
+) -> Result<Option<Checksum>, D::Error> {
+    let expected = String::deserialize(d)?;
+    Checksum::new(algorithm, &expected)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug)]
+pub(crate) struct HostConfig {
+    /// Overrides `--max-per-host` for this host.
+    pub(crate) max_connections: Option<NonZeroUsize>,
+    /// Headers to send with requests to this host. Headers set on the entry take precedence.
+    pub(crate) headers: HeaderMap,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawHostConfig {
+    #[serde(default)]
+    max_connections: Option<NonZeroUsize>,
+    #[serde(default)]
+    headers: BTreeMap<String, HeaderSource>,
+}
+
+/// A pattern matching host names: either an exact host name, or `*.example.com` to match any
+/// subdomain of `example.com`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum HostPattern {
+    Exact(String),
+    /// Matches subdomains of this domain, but not the domain itself.
+    Subdomains(String),
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Result<Self, String> {
+        let pattern = pattern.to_ascii_lowercase();
+        let parsed = match pattern.strip_prefix("*.") {
+            Some(domain) => Self::Subdomains(domain.to_owned()),
+            None => Self::Exact(pattern.clone()),
+        };
+        match &parsed {
+            Self::Exact(host) | Self::Subdomains(host) if !host.is_empty() && !host.contains('*') => {
+                Ok(parsed)
+            }
+            _ => Err(format!(
+                "invalid host pattern `{pattern}`: expected a host name, or `*.` followed by a domain"
+            )),
+        }
+    }
+
+    /// Returns true if `host` matches this pattern.
+    pub(crate) fn matches(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        match self {
+            Self::Exact(exact) => host == *exact,
+            Self::Subdomains(domain) => host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.')),
+        }
+    }
+
+    /// Returns a key ordering patterns from the least to the most specific. Exact host names are
+    /// the most specific, and otherwise longer domains are more specific.
+    fn specificity(&self) -> (bool, usize) {
+        match self {
+            Self::Exact(host) => (true, host.len()),
+            Self::Subdomains(domain) => (false, domain.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fallback() -> FallbackOptions {
+        FallbackOptions {
+            idle_timeout: Duration::from_secs(60),
+            timeout: None,
+            retries: 3,
+            segments: NonZeroUsize::new(1).unwrap(),
+        }
+    }
+
+    fn parse_toml(contents: &str) -> Result<Manifest, String> {
+        Manifest::from_contents(contents, ManifestFormat::Toml, &fallback())
+    }
+
+    /// Returns the path of the only entry in a manifest.
+    fn entry_path(contents: &str) -> Result<Utf8PathBuf, String> {
+        let mut manifest = parse_toml(contents)?;
+        assert_eq!(manifest.downloads.len(), 1);
+        Ok(manifest.downloads.remove(0).path)
+    }
+
+    #[test]
+    fn paths_within_out_dir() {
+        let path = entry_path(
+            r#"
+            [[downloads]]
+            url = "https://example.com/files/a.iso"
+            "#,
+        );
+        assert_eq!(path.unwrap(), "a.iso");
+
+        let path = entry_path(
+            r#"
+            [[downloads]]
+            url = "https://example.com/files/a.iso"
+            directory = "isos/linux"
+            file_name = "debian/b.iso"
+            "#,
+        );
+        assert_eq!(path.unwrap(), "isos/linux/debian/b.iso");
+
+        // A URL without a file name is saved as index.html.
+        let path = entry_path(
+            r#"
+            [[downloads]]
+            url = "https://example.com/"
+            "#,
+        );
+        assert_eq!(path.unwrap(), "index.html");
+    }
+
+    #[test]
+    fn paths_escaping_out_dir() {
+        let cases = [
+            (r#"file_name = "../escaped.bin""#, "../escaped.bin"),
+            (
+                r#"file_name = "a/../../escaped.bin""#,
+                "a/../../escaped.bin",
+            ),
+            (r#"file_name = "/etc/passwd""#, "/etc/passwd"),
+            (r#"file_name = """#, ""),
+            (r#"directory = "..""#, "../a.iso"),
+            (r#"directory = "/tmp""#, "/tmp/a.iso"),
+            (r#"directory = "./isos""#, "./isos/a.iso"),
+            (
+                "directory = \"isos\"\nfile_name = \"../../escaped.bin\"",
+                "isos/../../escaped.bin",
+            ),
+        ];
+        for (options, path) in cases {
+            let contents =
+                format!("[[downloads]]\nurl = \"https://example.com/files/a.iso\"\n{options}\n");
+            let error = entry_path(&contents).expect_err(options);
+            assert!(
+                error.contains(&format!("path `{path}`")),
+                "for {options}: unexpected error: {error}",
+            );
+        }
+    }
+
+    #[test]
+    fn paths_escaping_out_dir_through_defaults() {
+        let error = entry_path(
+            r#"
+            [defaults]
+            directory = "../elsewhere"
+
+            [[downloads]]
+            url = "https://example.com/files/a.iso"
+            "#,
+        )
+        .unwrap_err();
+        assert!(error.starts_with("at `downloads[0]`:"), "{error}");
+    }
+
+    #[test]
+    fn from_url_paths() {
+        let url = Url::parse("https://example.com/files/a.iso").unwrap();
+        let entry = ManifestEntry::from_url(url.clone(), None, &fallback()).unwrap();
+        assert_eq!(entry.path, "a.iso");
+
+        let entry =
+            ManifestEntry::from_url(url.clone(), Some("b.iso".to_owned()), &fallback()).unwrap();
+        assert_eq!(entry.path, "b.iso");
+
+        for file_name in ["../escaped.bin", "/etc/passwd", ""] {
+            ManifestEntry::from_url(url.clone(), Some(file_name.to_owned()), &fallback())
+                .expect_err(file_name);
+        }
+    }
+
+    #[test]
+    fn defaults_merging() {
+        let manifest = parse_toml(
+            r#"
+            [defaults]
+            directory = "isos"
+            retries = 5
+            idle_timeout = "10s"
+
+            [[downloads]]
+            url = "https://example.com/a.iso"
+
+            [[downloads]]
+            url = "https://example.com/b.iso"
+            directory = "other"
+            retries = 0
+            timeout = "1h"
+            "#,
+        )
+        .unwrap();
+
+        let a = &manifest.downloads[0];
+        assert_eq!(a.path, "isos/a.iso");
+        assert_eq!(a.retries, 5);
+        assert_eq!(a.idle_timeout, Duration::from_secs(10));
+        // Neither the entry nor the defaults set these, so they come from the command line.
+        assert_eq!(a.timeout, None);
+        assert_eq!(a.segments.get(), 1);
+
+        let b = &manifest.downloads[1];
+        assert_eq!(b.path, "other/b.iso");
+        assert_eq!(b.retries, 0);
+        assert_eq!(b.idle_timeout, Duration::from_secs(10));
+        assert_eq!(b.timeout, Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn headers_from_env() {
+        let contents = r#"
+            [defaults]
+            headers = { Authorization = { env = "DOWNLOAD_MANAGER_TEST_UNSET" } }
+
+            [[downloads]]
+            url = "https://example.com/a.iso"
+            sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+            headers = { X-Token = { env = "DOWNLOAD_MANAGER_TEST_UNSET" } }
+
+            [hosts."example.com"]
+            headers = { Cookie = { env = "DOWNLOAD_MANAGER_TEST_UNSET" } }
+            "#;
+        let error = parse_toml(contents).unwrap_err();
+        assert!(error.contains("DOWNLOAD_MANAGER_TEST_UNSET"), "{error}");
+
+        // Without headers, the manifest loads, and paths and checksums are still resolved.
+        let manifest = Manifest::parse(contents, ManifestFormat::Toml)
+            .and_then(|raw| raw.without_headers().resolve(&fallback()))
+            .unwrap();
+        let entry = &manifest.downloads[0];
+        assert_eq!(entry.path, "a.iso");
+        assert_eq!(entry.checksums.len(), 1);
+        assert!(entry.default_headers.is_empty() && entry.headers.is_empty());
+        assert!(manifest.hosts[0].1.headers.is_empty());
+    }
+
+    #[test]
+    fn host_patterns() {
+        let exact = HostPattern::parse("Example.com").unwrap();
+        assert!(exact.matches("example.com"));
+        assert!(exact.matches("EXAMPLE.COM"));
+        assert!(!exact.matches("www.example.com"));
+
+        let subdomains = HostPattern::parse("*.example.com").unwrap();
+        assert!(subdomains.matches("www.example.com"));
+        assert!(subdomains.matches("a.b.example.com"));
+        assert!(!subdomains.matches("example.com"));
+        assert!(!subdomains.matches("badexample.com"));
+
+        for invalid in ["", "*.", "*", "a.*.com"] {
+            HostPattern::parse(invalid).expect_err(invalid);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! The progress display shown while downloading.
+//!
+//! If standard error is a terminal, each active download gets a progress bar, along with a line
+//! showing overall progress. Otherwise, or with `--message-format json`, the bars are hidden and
+//! workers report their status periodically instead.
+
+use camino::Utf8Path;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::{
+    io::{self, IsTerminal, Write},
+    time::Duration,
+};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The progress display for a run.
+#[derive(Clone, Debug)]
+pub(crate) struct Progress {
+    multi: MultiProgress,
+    /// Overall progress. The length is the total size of the downloads started so far, where
+    /// known.
+    total: ProgressBar,
+}
+
+impl Progress {
+    /// Creates a new progress display, which is shown if `enabled` is true and standard error is a
+    /// terminal.
+    pub(crate) fn new(enabled: bool) -> Self {
+        let target = if enabled && io::stderr().is_terminal() {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::hidden()
+        };
+        let multi = MultiProgress::with_draw_target(target);
+        let total = multi.add(ProgressBar::new(0).with_style(total_style()));
+        // Keep the speed and ETA up to date even if no data is being received.
+        total.enable_steady_tick(Duration::from_millis(500));
+        Self { multi, total }
+    }
+
+    /// Returns true if progress bars are being shown.
+    pub(crate) fn is_visible(&self) -> bool {
+        !self.multi.is_hidden()
+    }
+
+    /// Adds a progress bar for a download to `path`.
+    pub(crate) fn add(&self, path: &Utf8Path) -> DownloadProgress {
+        let bar = ProgressBar::new(0)
+            .with_style(spinner_style())
+            .with_prefix(path.file_name().unwrap_or(path.as_str()).to_owned());
+        // Keep the overall progress line at the bottom.
+        let bar = self.multi.insert_before(&self.total, bar);
+        DownloadProgress {
+            multi: self.multi.clone(),
+            total: self.total.clone(),
+            bar,
+        }
+    }
+
+    /// Updates the number of files shown on the overall progress line.
+    pub(crate) fn set_files(&self, finished: usize, total: usize) {
+        self.total.set_message(format!("{finished}/{total} files"));
+    }
+
+    /// Clears the progress bars from the terminal. They're drawn again on the next update.
+    pub(crate) fn clear(&self) {
+        _ = self.multi.clear();
+    }
+
+    /// Redraws the progress bars from scratch, e.g. because the terminal was resized.
+    pub(crate) fn redraw(&self) {
+        self.clear();
+        self.total.tick();
+    }
+
+    /// Removes the overall progress line, once all downloads are done.
+    pub(crate) fn finish(&self) {
+        self.total.finish_and_clear();
+    }
+
+    /// Returns a writer for log lines, which hides the progress bars while writing so that the two
+    /// don't get mixed up.
+    pub(crate) fn log_writer(&self) -> LogWriter {
+        LogWriter {
+            multi: self.multi.clone(),
+        }
+    }
+}
+
+/// The progress bar for a single download. The bar is removed when this is dropped.
+#[derive(Debug)]
+pub(crate) struct DownloadProgress {
+    multi: MultiProgress,
+    total: ProgressBar,
+    bar: ProgressBar,
+}
+
+impl DownloadProgress {
+    /// Called when data starts being received, with the size of the file if it's known and the
+    /// number of bytes already downloaded.
+    pub(crate) fn start(&self, len: Option<u64>, pos: u64) {
+        let previous_len = self.bar.length().unwrap_or(0);
+        let len_known = len.is_some();
+        let len = len.unwrap_or(0);
+        self.total
+            .set_length((self.total.length().unwrap_or(0) + len).saturating_sub(previous_len));
+        self.bar.set_length(len);
+        self.bar.set_style(if len_known {
+            bar_style()
+        } else {
+            spinner_style()
+        });
+        self.set_position(pos);
+        // The speed and ETA should only count data received from here on.
+        self.bar.reset_eta();
+    }
+
+    /// Sets the number of bytes downloaded so far.
+    pub(crate) fn set_position(&self, pos: u64) {
+        let previous = self.bar.position();
+        self.total
+            .set_position((self.total.position() + pos).saturating_sub(previous));
+        self.bar.set_position(pos);
+    }
+}
+
+impl Drop for DownloadProgress {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+        self.multi.remove(&self.bar);
+    }
+}
+
+fn bar_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "{prefix:>24.bold} [{bar:30}] {binary_bytes:>10}/{binary_total_bytes:<10} \
+         {binary_bytes_per_sec:>12} ETA {eta}",
+    )
+    .expect("template is valid")
+    .progress_chars("=> ")
+}
+
+fn spinner_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "{prefix:>24.bold} {spinner} {binary_bytes:>10} {binary_bytes_per_sec:>12}",
+    )
+    .expect("template is valid")
+}
+
+fn total_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "{msg:>24.bold} [{bar:30}] {binary_bytes:>10}/{binary_total_bytes:<10} \
+         {binary_bytes_per_sec:>12} ETA {eta}",
+    )
+    .expect("template is valid")
+    .progress_chars("=> ")
+}
+
+/// Writes log lines to standard output, hiding the progress bars while doing so.
+#[derive(Clone, Debug)]
+pub(crate) struct LogWriter {
+    multi: MultiProgress,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // tracing-subscriber writes each event with a single call, so the bars are only hidden
+        // once per log line.
+        self.multi.suspend(|| io::stdout().write(buf))
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.multi.suspend(|| io::stdout().write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = LogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
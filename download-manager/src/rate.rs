@@ -0,0 +1,179 @@
+//! Bandwidth limits for downloads.
+//!
+//! Limits are implemented as token buckets. Rather than waiting for tokens to become available,
+//! downloads take the tokens for each chunk of data as it's received, going into debt if necessary,
+//! and then pause reading for as long as it takes to pay the debt back. Downloads sharing a bucket
+//! queue up behind each other's debt, so the rate is shared evenly between them.
+
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    fmt,
+    num::NonZeroU64,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// The longest burst a bucket allows after being idle, expressed as time at the full rate.
+const BURST: Duration = Duration::from_millis(250);
+
+/// A rate in bytes per second.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Rate(NonZeroU64);
+
+impl Rate {
+    pub(crate) fn bytes_per_sec(self) -> u64 {
+        self.0.get()
+    }
+
+    /// Returns this rate multiplied by `factor`, saturating at the maximum and never going below 1
+    /// byte per second.
+    pub(crate) fn scale(self, factor: f64) -> Self {
+        let scaled = (self.0.get() as f64 * factor).clamp(1.0, u64::MAX as f64);
+        Self(NonZeroU64::new(scaled as u64).unwrap_or(NonZeroU64::MIN))
+    }
+}
+
+const UNITS: [(char, u64); 3] = [('G', 1 << 30), ('M', 1 << 20), ('K', 1 << 10)];
+
+impl FromStr for Rate {
+    type Err = String;
+
+    /// Parses a rate such as `500K` or `2M`. Suffixes are binary, so `1K` is 1024 bytes per second.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate `{s}`: expected bytes per second, e.g. 500K or 2M");
+        let (digits, multiplier) = match s.char_indices().last() {
+            Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+                let (_, multiplier) = UNITS
+                    .iter()
+                    .find(|(unit, _)| unit.eq_ignore_ascii_case(&suffix))
+                    .ok_or_else(invalid)?;
+                (&s[..index], *multiplier)
+            }
+            _ => (s, 1),
+        };
+        let rate = digits
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .and_then(NonZeroU64::new)
+            .ok_or_else(invalid)?;
+        Ok(Self(rate))
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rate = self.0.get();
+        match UNITS.iter().find(|(_, size)| rate % size == 0) {
+            Some((unit, size)) => write!(f, "{}{unit}/s", rate / size),
+            None => write!(f, "{rate}/s"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Rate {
+    /// Rates can be written either as a number of bytes per second or as a string like `500K`.
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RateRepr {
+            Bytes(NonZeroU64),
+            String(String),
+        }
+
+        match RateRepr::deserialize(d)? {
+            RateRepr::Bytes(bytes) => Ok(Self(bytes)),
+            RateRepr::String(s) => s.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+/// A token bucket limiting the rate at which data is received.
+///
+/// The rate can be changed at any time, including while downloads are running.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// The rate, or `None` for no limit.
+    rate: Option<Rate>,
+    /// The number of bytes that can be received without waiting. This is negative if downloads
+    /// have taken more than was available.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: Option<Rate>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub(crate) fn rate(&self) -> Option<Rate> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Changes the rate. Any outstanding debt is paid back at the new rate.
+    pub(crate) fn set_rate(&self, rate: Option<Rate>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.rate = rate;
+    }
+
+    /// Takes tokens for `bytes` bytes that were just received, returning how long to wait before
+    /// receiving more.
+    pub(crate) fn reserve(&self, bytes: u64) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        let Some(rate) = bucket.rate else {
+            return Duration::ZERO;
+        };
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate.bytes_per_sec() as f64)
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated);
+        self.updated = now;
+        self.tokens = match self.rate {
+            Some(rate) => {
+                let rate = rate.bytes_per_sec() as f64;
+                (self.tokens + elapsed.as_secs_f64() * rate).min(BURST.as_secs_f64() * rate)
+            }
+            // Without a limit, there's no debt to pay back.
+            None => 0.0,
+        };
+    }
+}
+
+/// The rate limiters that apply to a single download: the global limit, and the download's own.
+#[derive(Clone, Debug)]
+pub(crate) struct Throttle {
+    pub(crate) global: Arc<RateLimiter>,
+    pub(crate) download: Arc<RateLimiter>,
+}
+
+impl Throttle {
+    /// Takes tokens for `bytes` bytes from both limiters, returning how long to wait before
+    /// receiving more.
+    pub(crate) fn reserve(&self, bytes: u64) -> Duration {
+        self.global.reserve(bytes).max(self.download.reserve(bytes))
+    }
+}
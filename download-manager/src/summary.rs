@@ -0,0 +1,96 @@
+//! The summary reported at the end of a run, and the exit code derived from it.
+
+use crate::command::CancelKind;
+use std::time::Duration;
+use url::Url;
+
+/// The exit code used when at least one download failed, or when `verify` finds a problem with a
+/// file.
+pub(crate) const SOME_FAILED_EXIT_CODE: u8 = 2;
+
+/// The exit code used when a second Ctrl-C or the shutdown timeout causes downloads to be
+/// abandoned.
+pub(crate) const FORCED_EXIT_CODE: u8 = 3;
+
+/// Counts of download outcomes in a single run.
+#[derive(Debug, Default)]
+pub(crate) struct RunSummary {
+    pub(crate) completed: usize,
+    /// The URLs of downloads that failed.
+    pub(crate) failed: Vec<Url>,
+    /// Download tasks that panicked. These count as failures.
+    pub(crate) panicked: usize,
+    /// Downloads stopped by a signal, including any abandoned by a forced exit.
+    pub(crate) cancelled: usize,
+    /// Manifest entries that weren't downloaded, because a previous run already completed them.
+    pub(crate) skipped: usize,
+    /// Downloads that completed from a mirror rather than their own URL, along with the mirror.
+    pub(crate) mirrors: Vec<(Url, Url)>,
+    /// The total number of bytes received in this run.
+    pub(crate) bytes: u64,
+    pub(crate) elapsed: Duration,
+}
+
+impl RunSummary {
+    /// Returns the number of failed downloads.
+    pub(crate) fn failed_count(&self) -> usize {
+        self.failed.len() + self.panicked
+    }
+
+    /// Logs the summary.
+    pub(crate) fn report(&self) {
+        // Round to the nearest millisecond for display.
+        let elapsed = Duration::from_millis(self.elapsed.as_millis() as u64);
+        let elapsed = humantime::format_duration(elapsed);
+        for (url, mirror) in &self.mirrors {
+            tracing::info!(url = %url, mirror = %mirror, "Downloaded from mirror");
+        }
+        if self.failed_count() == 0 {
+            tracing::info!(
+                completed = self.completed,
+                failed = 0,
+                cancelled = self.cancelled,
+                skipped = self.skipped,
+                bytes = self.bytes,
+                elapsed = %elapsed,
+                "Run finished",
+            );
+        } else {
+            for url in &self.failed {
+                tracing::error!(url = %url, "Failed download");
+            }
+            tracing::error!(
+                completed = self.completed,
+                failed = self.failed_count(),
+                cancelled = self.cancelled,
+                skipped = self.skipped,
+                bytes = self.bytes,
+                elapsed = %elapsed,
+                "Run finished with failures",
+            );
+        }
+    }
+
+    /// Returns the exit code for the run.
+    ///
+    /// In order of precedence:
+    ///
+    /// * A forced exit (see `FORCED_EXIT_CODE`) exits with 3.
+    /// * A run stopped by a signal exits with 128 + the signal number, as shells do. (A daemon asked
+    ///   to shut down by a client wasn't stopped by a signal.)
+    /// * A run where any download failed exits with 2.
+    /// * Otherwise, the run exits with 0.
+    ///
+    /// Errors that stop the download manager itself, e.g. an invalid manifest, exit with 1.
+    pub(crate) fn exit_code(&self, shutting_down: Option<CancelKind>, forced_exit: bool) -> u8 {
+        if forced_exit {
+            FORCED_EXIT_CODE
+        } else if let Some(signo) = shutting_down.and_then(CancelKind::signal_number) {
+            128 + signo
+        } else if self.failed_count() > 0 {
+            SOME_FAILED_EXIT_CODE
+        } else {
+            0
+        }
+    }
+}